@@ -1,34 +1,47 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{fs, io};
 use tdk_electrum::electrum_client::{self, ElectrumApi};
 use tdk_electrum::BdkElectrumClient;
 use tdk_esplora::esplora_client;
 use tdk_esplora::esplora_client::{deserialize, OutputStatus};
 use tdk_esplora::EsploraExt;
-use tdk_sqlite::{rusqlite::Connection, Store};
+use tdk_sqlite::rusqlite::{self, Connection, OptionalExtension};
+use tdk_sqlite::Store;
+use tdk_wallet::chain::spk_client::SyncRequest;
+use tdk_wallet::chain::ChainPosition;
 use tdk_wallet::descriptor::{Descriptor, DescriptorPublicKey};
 use tdk_wallet::miniscript::descriptor::DescriptorSecretKey;
 use tdk_wallet::miniscript::ToPublicKey;
 use tdk_wallet::signer::SignerId;
+use tdk_wallet::tapyrus::address::NetworkUnchecked;
 use tdk_wallet::tapyrus::bip32::{ChildNumber, Xpriv};
+use tdk_wallet::tapyrus::consensus::encode::VarInt;
 use tdk_wallet::tapyrus::consensus::serialize;
 use tdk_wallet::tapyrus::hex::{DisplayHex, FromHex};
 use tdk_wallet::tapyrus::script::color_identifier::ColorIdentifier;
+use tdk_wallet::tapyrus::secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use tdk_wallet::tapyrus::secp256k1::hashes::hmac::{Hmac, HmacEngine};
 use tdk_wallet::tapyrus::secp256k1::hashes::sha256;
+use tdk_wallet::tapyrus::secp256k1::hashes::sha256d;
 use tdk_wallet::tapyrus::secp256k1::hashes::Hash;
+use tdk_wallet::tapyrus::secp256k1::hashes::HashEngine;
+use tdk_wallet::tapyrus::secp256k1::rand::seq::SliceRandom;
 use tdk_wallet::tapyrus::secp256k1::rand::Rng;
 use tdk_wallet::tapyrus::secp256k1::Message;
 use tdk_wallet::tapyrus::secp256k1::ThirtyTwoByteHash;
 use tdk_wallet::tapyrus::{base64, secp256k1, Address, BlockHash, PublicKey, ScriptBuf};
 use tdk_wallet::tapyrus::{Amount, MalFixTxid, OutPoint, Transaction};
 use tdk_wallet::template::Bip44;
-use tdk_wallet::wallet::tx_builder::AddUtxoError;
-use tdk_wallet::wallet::NewOrLoadError;
+use tdk_wallet::wallet::tx_builder::{AddUtxoError, TxOrdering};
+use tdk_wallet::wallet::{NewOrLoadError, Update};
 use tdk_wallet::{tapyrus, KeychainKind, SignOptions, Wallet};
 
 #[derive(PartialEq, Clone, Debug)]
@@ -56,6 +69,41 @@ impl From<tapyrus::network::Network> for Network {
     }
 }
 
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub(crate) enum Keychain {
+    External,
+    Internal,
+}
+
+impl From<Keychain> for KeychainKind {
+    fn from(keychain: Keychain) -> Self {
+        match keychain {
+            Keychain::External => KeychainKind::External,
+            Keychain::Internal => KeychainKind::Internal,
+        }
+    }
+}
+
+impl From<KeychainKind> for Keychain {
+    fn from(keychain: KeychainKind) -> Self {
+        match keychain {
+            KeychainKind::External => Keychain::External,
+            KeychainKind::Internal => Keychain::Internal,
+        }
+    }
+}
+
+/// Provides the bearer token for the esplora authentication.
+pub(crate) trait EsploraTokenProvider: Send + Sync {
+    fn token(&self) -> String;
+}
+
+impl Debug for dyn EsploraTokenProvider {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EsploraTokenProvider")
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct Config {
     pub network_mode: Network,
@@ -69,6 +117,8 @@ pub(crate) struct Config {
     pub master_key_path: Option<String>,
     pub master_key: Option<String>,
     pub db_file_path: Option<String>,
+    pub esplora_headers: Option<HashMap<String, String>>,
+    pub esplora_token_provider: Option<Arc<dyn EsploraTokenProvider>>,
 }
 
 impl Config {
@@ -85,6 +135,8 @@ impl Config {
         master_key_path: Option<String>,
         master_key: Option<String>,
         db_file_path: Option<String>,
+        esplora_headers: Option<HashMap<String, String>>,
+        esplora_token_provider: Option<Box<dyn EsploraTokenProvider>>,
     ) -> Self {
         Config {
             network_mode,
@@ -98,6 +150,8 @@ impl Config {
             master_key_path,
             master_key,
             db_file_path,
+            esplora_headers,
+            esplora_token_provider: esplora_token_provider.map(Arc::from),
         }
     }
 }
@@ -108,6 +162,8 @@ pub(crate) enum BackendClient {
         url: String,
         user: Option<String>,
         password: Option<String>,
+        headers: HashMap<String, String>,
+        token_provider: Option<Arc<dyn EsploraTokenProvider>>,
     },
     Electrum {
         url: String,
@@ -118,6 +174,68 @@ pub(crate) struct HdWallet {
     network: tapyrus::network::Network,
     wallet: Mutex<Wallet>,
     backend: BackendClient,
+    metadata: Metadata,
+    sync_lock: Mutex<()>,
+    notification_worker: Mutex<Option<Worker>>,
+    sync_worker: Mutex<Option<Worker>>,
+    event_listener: Mutex<Option<Arc<dyn WalletEventListener>>>,
+    contract_registry: Mutex<Option<Arc<dyn ContractRegistry>>>,
+}
+
+/// Receives the real-time notifications from the electrum server.
+pub(crate) trait NotificationListener: Send + Sync {
+    fn on_new_block(&self, height: u32, block_hash: String);
+    fn on_new_transaction(&self, txid: String);
+    fn on_transaction_confirmed(&self, txid: String, height: u32);
+}
+
+/// Receives the results of the background sync.
+pub(crate) trait SyncListener: Send + Sync {
+    fn on_sync_completed(&self, result: SyncResult);
+    fn on_sync_failed(&self, cause_description: String);
+}
+
+/// The change of the wallet found by the sync.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum WalletEvent {
+    TransactionReceived {
+        txid: String,
+    },
+    TransactionConfirmed {
+        txid: String,
+        height: u32,
+    },
+    /// The transaction is no longer in the best chain or mempool. e.g. replaced by another transaction.
+    TransactionEvicted {
+        txid: String,
+    },
+    BalanceChanged {
+        color_id: Option<String>,
+        old_balance: u64,
+        new_balance: u64,
+    },
+    /// The blocks the wallet was synced to are disconnected from the best chain.
+    Reorg {
+        reorg: ReorgInfo,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ReorgInfo {
+    /// The height of the last block both the old and the new chain have.
+    pub fork_height: u32,
+    /// The transactions which were confirmed in the disconnected blocks or whose confirmation is changed.
+    pub affected_txids: Vec<String>,
+}
+
+/// Receives the wallet events.
+pub(crate) trait WalletEventListener: Send + Sync {
+    fn on_event(&self, event: WalletEvent);
+}
+
+/// Provides the contracts which may have been paid to the wallet by the pay to contract protocol.
+pub(crate) trait ContractRegistry: Send + Sync {
+    fn find_contracts(&self, payment_bases: Vec<String>) -> Vec<Contract>;
 }
 
 pub(crate) struct TransferParams {
@@ -125,6 +243,22 @@ pub(crate) struct TransferParams {
     pub to_address: String,
 }
 
+pub(crate) struct TransferOptions {
+    pub change_address: Option<String>,
+    pub colored_change_address: Option<String>,
+    pub allow_change: bool,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            change_address: None,
+            colored_change_address: None,
+            allow_change: true,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct TxOut {
     pub txid: String,
@@ -141,26 +275,245 @@ pub(crate) struct Contract {
     pub contract: String,
     pub payment_base: String,
     pub payable: bool,
+    pub encoding: Option<ContractEncoding>,
 }
 
 impl From<tdk_wallet::chain::Contract> for Contract {
     fn from(contract: tdk_wallet::chain::Contract) -> Self {
+        let (content, encoding) = ContractEncoding::Utf8.encode(&contract.contract);
         Contract {
             contract_id: contract.contract_id,
-            contract: String::from_utf8(contract.contract).unwrap(),
+            contract: content,
             payment_base: contract.payment_base.to_string(),
             payable: contract.spendable,
+            encoding: Some(encoding),
+        }
+    }
+}
+
+impl From<StoredContract> for Contract {
+    fn from(contract: StoredContract) -> Self {
+        let (content, encoding) = contract.encoding.encode(&contract.contract);
+        Contract {
+            contract_id: contract.contract_id,
+            contract: content,
+            payment_base: contract.payment_base,
+            payable: contract.payable,
+            encoding: Some(encoding),
+        }
+    }
+}
+
+/// The encoding of the contract string into the bytes committed by the pay to contract address.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum ContractEncoding {
+    Utf8,
+    Hex,
+    Base64,
+    Json,
+}
+
+impl ContractEncoding {
+    /// Decode the contract string into the bytes.
+    /// The JSON document is serialized canonically by the JSON Canonicalization Scheme (RFC 8785).
+    fn decode(self, contract: &str) -> Result<Vec<u8>, String> {
+        match self {
+            ContractEncoding::Utf8 => Ok(contract.as_bytes().to_vec()),
+            ContractEncoding::Hex => Vec::from_hex(contract).map_err(|e| e.to_string()),
+            ContractEncoding::Base64 => {
+                use base64::prelude::*;
+                BASE64_STANDARD.decode(contract).map_err(|e| e.to_string())
+            }
+            ContractEncoding::Json => {
+                let value: serde_json::Value =
+                    serde_json::from_str(contract).map_err(|e| e.to_string())?;
+                let mut canonical = String::new();
+                canonicalize_json(&value, &mut canonical)?;
+                Ok(canonical.into_bytes())
+            }
+        }
+    }
+
+    /// Encode the bytes into the contract string.
+    /// The bytes which can not be represented in the encoding, such as non UTF-8 bytes, are encoded in hex.
+    fn encode(self, contract: &[u8]) -> (String, ContractEncoding) {
+        match self {
+            ContractEncoding::Utf8 | ContractEncoding::Json => {
+                match std::str::from_utf8(contract) {
+                    Ok(s) => (s.to_string(), self),
+                    Err(_) => (contract.to_lower_hex_string(), ContractEncoding::Hex),
+                }
+            }
+            ContractEncoding::Hex => (contract.to_lower_hex_string(), self),
+            ContractEncoding::Base64 => {
+                use base64::prelude::*;
+                (BASE64_STANDARD.encode(contract), self)
+            }
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ContractEncoding::Utf8 => "utf8",
+            ContractEncoding::Hex => "hex",
+            ContractEncoding::Base64 => "base64",
+            ContractEncoding::Json => "json",
+        }
+    }
+}
+
+impl FromStr for ContractEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(ContractEncoding::Utf8),
+            "hex" => Ok(ContractEncoding::Hex),
+            "base64" => Ok(ContractEncoding::Base64),
+            "json" => Ok(ContractEncoding::Json),
+            _ => Err(format!("Unknown contract encoding: {}", s)),
         }
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum RefundStatus {
+    Unspent,
+    Refunded,
+    Spent,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct TransferredOutput {
+    pub index: u32,
+    pub amount: u64,
+    pub address: String,
+    pub status: RefundStatus,
+    pub spending_txid: Option<String>,
+    pub refund_txid: Option<String>,
+    pub refund_amount: u64,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct TrustLayerRefundReport {
+    pub txid: String,
+    pub color_id: Option<String>,
+    pub outputs: Vec<TransferredOutput>,
+    pub total_refund: u64,
+    pub unresolved_outputs: Vec<u32>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct TrustLayerRefundRequest {
+    pub txid: String,
+    pub color_id: Option<String>,
+    pub refund_address: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct TrustLayerRefundResult {
+    pub txid: String,
+    pub color_id: Option<String>,
+    pub report: Option<TrustLayerRefundReport>,
+    pub error: Option<String>,
+}
+
+pub(crate) struct ChainTip {
+    pub height: u32,
+    pub block_hash: String,
+}
+
+pub(crate) struct SyncStatus {
+    pub local_tip: ChainTip,
+    pub last_sync_time: Option<u64>,
+    pub full_scan_completed: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SyncResult {
+    pub new_transactions: u32,
+    pub updated_transactions: u32,
+    pub newly_used_addresses: u32,
+    pub last_revealed_external_index: Option<u32>,
+    pub last_revealed_internal_index: Option<u32>,
+    pub tip_height: u32,
+    pub elapsed_ms: u64,
+    pub reorg: Option<ReorgInfo>,
+    pub discovered_contracts: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ColoredAmount {
+    pub color_id: Option<String>,
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct AddressInfo {
+    pub keychain: Keychain,
+    pub index: u32,
+    pub address: String,
+    pub public_key: String,
+    pub used: bool,
+    pub received: Vec<ColoredAmount>,
+}
+
 pub(crate) struct GetNewAddressResult {
     pub address: String,
     pub public_key: String,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum AddressType {
+    P2pkh,
+    P2sh,
+    Cp2pkh,
+    Cp2sh,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct AddressDetails {
+    pub network: Network,
+    pub address_type: AddressType,
+    pub color_id: Option<String>,
+    pub script_pubkey: String,
+    pub is_mine: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct UncoloredAddress {
+    pub address: String,
+    pub color_id: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct VerifyP2cAddressResult {
+    pub matches: bool,
+    pub expected_address: String,
+    pub commitment: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(crate) struct PaymentRequest {
+    pub address: String,
+    pub amount: Option<u64>,
+    pub color_id: Option<String>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    pub payment_base: Option<String>,
+    pub contract: Option<String>,
+    pub contract_encoding: Option<ContractEncoding>,
+}
+
 const SYNC_PARALLEL_REQUESTS: usize = 1;
 const STOP_GAP: usize = 25;
+const PAYMENT_URI_SCHEME: &str = "tapyrus";
+const NOTIFICATION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const LAST_SYNC_TIME_KEY: &str = "last_sync_time";
+const FULL_SCAN_COMPLETED_KEY: &str = "full_scan_completed";
+const CONTRACT_BUNDLE_VERSION: u64 = 1;
+const CONTRACT_BUNDLE_KEY_TAG: &[u8] = b"tapyrus-wallet-ffi/contract-bundle";
+const SIGNED_MESSAGE_PREFIX: &str = "Bitcoin Signed Message:\n";
 
 // Error type for the wallet
 #[derive(Debug)]
@@ -268,6 +621,61 @@ impl Display for SyncError {
 
 impl std::error::Error for SyncError {}
 
+#[derive(Debug)]
+pub(crate) enum SyncAddressesError {
+    FailedToParseAddress { address: String },
+    WrongNetworkAddress { address: String },
+    UnknownAddress { address: String },
+    EsploraClientError { cause_description: String },
+    ElectrumClientError { cause_description: String },
+    UpdateWalletError { cause_description: String },
+}
+
+impl Display for SyncAddressesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncAddressesError::FailedToParseAddress { address: e } => {
+                write!(f, "Failed to parse address: {}", e)
+            }
+            SyncAddressesError::WrongNetworkAddress { address: e } => {
+                write!(f, "Wrong network address: {}", e)
+            }
+            SyncAddressesError::UnknownAddress { address: e } => {
+                write!(f, "Unknown address: {}", e)
+            }
+            SyncAddressesError::EsploraClientError {
+                cause_description: e,
+            } => write!(f, "Esplora client error: {}", e),
+            SyncAddressesError::ElectrumClientError {
+                cause_description: e,
+            } => write!(f, "Electrum client error: {}", e),
+            SyncAddressesError::UpdateWalletError {
+                cause_description: e,
+            } => {
+                write!(f, "Failed to update wallet: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SyncAddressesError {}
+
+impl From<SyncError> for SyncAddressesError {
+    fn from(e: SyncError) -> Self {
+        match e {
+            SyncError::EsploraClientError { cause_description } => {
+                SyncAddressesError::EsploraClientError { cause_description }
+            }
+            SyncError::ElectrumClientError { cause_description } => {
+                SyncAddressesError::ElectrumClientError { cause_description }
+            }
+            SyncError::UpdateWalletError { cause_description } => {
+                SyncAddressesError::UpdateWalletError { cause_description }
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum GetNewAddressError {
     InvalidColorId,
@@ -283,6 +691,116 @@ impl Display for GetNewAddressError {
 
 impl std::error::Error for GetNewAddressError {}
 
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum PeekAddressError {
+    InvalidIndex,
+    InvalidColorId,
+}
+
+impl Display for PeekAddressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeekAddressError::InvalidIndex => write!(f, "Invalid index"),
+            PeekAddressError::InvalidColorId => write!(f, "Invalid color id"),
+        }
+    }
+}
+
+impl std::error::Error for PeekAddressError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum ParseAddressError {
+    FailedToParseAddress { address: String },
+    UnsupportedAddressType { address: String },
+}
+
+impl Display for ParseAddressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseAddressError::FailedToParseAddress { address: e } => {
+                write!(f, "Failed to parse address: {}", e)
+            }
+            ParseAddressError::UnsupportedAddressType { address: e } => {
+                write!(f, "Unsupported address type: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseAddressError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum ConvertAddressError {
+    FailedToParseAddress { address: String },
+    InvalidColorId,
+    AlreadyColored { address: String },
+    NotColored { address: String },
+    UnsupportedAddressType { address: String },
+}
+
+impl Display for ConvertAddressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConvertAddressError::FailedToParseAddress { address: e } => {
+                write!(f, "Failed to parse address: {}", e)
+            }
+            ConvertAddressError::InvalidColorId => write!(f, "Invalid color id"),
+            ConvertAddressError::AlreadyColored { address: e } => {
+                write!(f, "Address is already colored: {}", e)
+            }
+            ConvertAddressError::NotColored { address: e } => {
+                write!(f, "Address is not colored: {}", e)
+            }
+            ConvertAddressError::UnsupportedAddressType { address: e } => {
+                write!(f, "Unsupported address type: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConvertAddressError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum PaymentUriError {
+    InvalidUri { cause_description: String },
+    FailedToParseAddress { address: String },
+    WrongNetworkAddress { address: String },
+    InvalidAmount { amount: String },
+    InvalidColorId,
+    ColorIdMismatch,
+    InvalidContract { cause_description: String },
+    UnknownRequiredParameter { name: String },
+}
+
+impl Display for PaymentUriError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentUriError::InvalidUri {
+                cause_description: e,
+            } => write!(f, "Invalid payment uri: {}", e),
+            PaymentUriError::FailedToParseAddress { address: e } => {
+                write!(f, "Failed to parse address: {}", e)
+            }
+            PaymentUriError::WrongNetworkAddress { address: e } => {
+                write!(f, "Wrong network address: {}", e)
+            }
+            PaymentUriError::InvalidAmount { amount: e } => write!(f, "Invalid amount: {}", e),
+            PaymentUriError::InvalidColorId => write!(f, "Invalid color id"),
+            PaymentUriError::ColorIdMismatch => {
+                write!(f, "Color id does not match the color of the address")
+            }
+            PaymentUriError::InvalidContract {
+                cause_description: e,
+            } => write!(f, "Invalid contract: {}", e),
+            PaymentUriError::UnknownRequiredParameter { name: e } => {
+                write!(f, "Unknown required parameter: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PaymentUriError {}
+
 #[derive(Debug)]
 pub(crate) enum BalanceError {
     InvalidColorId,
@@ -309,6 +827,8 @@ pub(crate) enum TransferError {
     InvalidTransferAmount { cause_description: String },
     UnknownUtxo { utxo: TxOut },
     FailedToCreateTransaction { cause_description: String },
+    ChangeNotAllowed,
+    InvalidChangeAddress { address: String },
 }
 
 impl Display for TransferError {
@@ -341,6 +861,15 @@ impl Display for TransferError {
             } => {
                 write!(f, "Failed to create transaction: {}", e)
             }
+            TransferError::ChangeNotAllowed => {
+                write!(
+                    f,
+                    "The transaction requires change output but it is not allowed"
+                )
+            }
+            TransferError::InvalidChangeAddress { address: e } => {
+                write!(f, "Invalid change address: {}", e)
+            }
         }
     }
 }
@@ -443,6 +972,35 @@ impl Display for CalcPayToContractAddressError {
 
 impl std::error::Error for CalcPayToContractAddressError {}
 
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum VerifyP2cAddressError {
+    FailedToParseAddress { address: String },
+    WrongNetworkAddress { address: String },
+    FailedToParsePublicKey,
+    ContractError { cause_description: String },
+}
+
+impl Display for VerifyP2cAddressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyP2cAddressError::FailedToParseAddress { address: e } => {
+                write!(f, "Failed to parse address: {}", e)
+            }
+            VerifyP2cAddressError::WrongNetworkAddress { address: e } => {
+                write!(f, "Wrong network address: {}", e)
+            }
+            VerifyP2cAddressError::FailedToParsePublicKey => {
+                write!(f, "Failed to parse public key")
+            }
+            VerifyP2cAddressError::ContractError {
+                cause_description: e,
+            } => write!(f, "Contract error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VerifyP2cAddressError {}
+
 #[derive(Debug)]
 pub(crate) enum StoreContractError {
     ContractError { cause_description: String },
@@ -481,6 +1039,157 @@ impl Display for UpdateContractError {
 
 impl std::error::Error for UpdateContractError {}
 
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum ListContractsError {
+    WalletDBError { cause_description: String },
+}
+
+impl Display for ListContractsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListContractsError::WalletDBError {
+                cause_description: e,
+            } => write!(f, "Wallet DB error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ListContractsError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum GetContractError {
+    UnknownContract,
+    WalletDBError { cause_description: String },
+}
+
+impl Display for GetContractError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GetContractError::UnknownContract => write!(f, "Unknown contract"),
+            GetContractError::WalletDBError {
+                cause_description: e,
+            } => write!(f, "Wallet DB error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GetContractError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum DeleteContractError {
+    UnknownContract,
+    ContractHasUnspentFunds,
+    ContractError { cause_description: String },
+    WalletDBError { cause_description: String },
+}
+
+impl Display for DeleteContractError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeleteContractError::UnknownContract => write!(f, "Unknown contract"),
+            DeleteContractError::ContractHasUnspentFunds => {
+                write!(f, "The contract has unspent funds")
+            }
+            DeleteContractError::ContractError {
+                cause_description: e,
+            } => write!(f, "Contract error: {}", e),
+            DeleteContractError::WalletDBError {
+                cause_description: e,
+            } => write!(f, "Wallet DB error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DeleteContractError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum ContractFundsError {
+    UnknownContract,
+    ContractError { cause_description: String },
+    WalletDBError { cause_description: String },
+}
+
+impl Display for ContractFundsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContractFundsError::UnknownContract => write!(f, "Unknown contract"),
+            ContractFundsError::ContractError {
+                cause_description: e,
+            } => write!(f, "Contract error: {}", e),
+            ContractFundsError::WalletDBError {
+                cause_description: e,
+            } => write!(f, "Wallet DB error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ContractFundsError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum ExportContractsError {
+    KeyError { cause_description: String },
+    WalletDBError { cause_description: String },
+}
+
+impl Display for ExportContractsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportContractsError::KeyError {
+                cause_description: e,
+            } => write!(f, "Key error: {}", e),
+            ExportContractsError::WalletDBError {
+                cause_description: e,
+            } => write!(f, "Wallet DB error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ExportContractsError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum ImportContractsError {
+    InvalidBundle { cause_description: String },
+    UnsupportedVersion { version: u64 },
+    WrongNetwork,
+    InvalidHmac,
+    KeyError { cause_description: String },
+    ContractError { cause_description: String },
+    WalletDBError { cause_description: String },
+}
+
+impl Display for ImportContractsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportContractsError::InvalidBundle {
+                cause_description: e,
+            } => write!(f, "Invalid contract bundle: {}", e),
+            ImportContractsError::UnsupportedVersion { version: e } => {
+                write!(f, "Unsupported contract bundle version: {}", e)
+            }
+            ImportContractsError::WrongNetwork => {
+                write!(f, "The contract bundle is for the other network")
+            }
+            ImportContractsError::InvalidHmac => {
+                write!(
+                    f,
+                    "The contract bundle is not created by this wallet or is modified"
+                )
+            }
+            ImportContractsError::KeyError {
+                cause_description: e,
+            } => write!(f, "Key error: {}", e),
+            ImportContractsError::ContractError {
+                cause_description: e,
+            } => write!(f, "Contract error: {}", e),
+            ImportContractsError::WalletDBError {
+                cause_description: e,
+            } => write!(f, "Wallet DB error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImportContractsError {}
+
 #[derive(Debug, PartialEq, Clone)]
 pub(crate) enum SignMessageError {
     FailedToParsePublicKey,
@@ -523,6 +1232,35 @@ impl Display for VerifySignError {
 
 impl std::error::Error for VerifySignError {}
 
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum VerifyMessageError {
+    FailedToParseAddress { address: String },
+    WrongNetworkAddress { address: String },
+    UnsupportedAddressType,
+    FailedToParseSignature,
+}
+
+impl Display for VerifyMessageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyMessageError::FailedToParseAddress { address: e } => {
+                write!(f, "Failed to parse address: {}", e)
+            }
+            VerifyMessageError::WrongNetworkAddress { address: e } => {
+                write!(f, "Wrong network address: {}", e)
+            }
+            VerifyMessageError::UnsupportedAddressType => {
+                write!(f, "Unsupported address type")
+            }
+            VerifyMessageError::FailedToParseSignature => {
+                write!(f, "Failed to parse signature")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyMessageError {}
+
 #[derive(Debug, PartialEq, Clone)]
 pub(crate) enum CheckTrustLayerRefundError {
     FailedToParseTxid { txid: String },
@@ -531,6 +1269,9 @@ pub(crate) enum CheckTrustLayerRefundError {
     UnknownTxid,
     CannotFoundRefundTransaction { txid: String },
     InvalidColorId,
+    FailedToParseAddress { address: String },
+    WrongNetworkAddress { address: String },
+    InvalidRefundAddress { address: String },
 }
 
 impl Display for CheckTrustLayerRefundError {
@@ -554,12 +1295,179 @@ impl Display for CheckTrustLayerRefundError {
                 write!(f, "Cannot found refund transaction: {}", e)
             }
             CheckTrustLayerRefundError::InvalidColorId => write!(f, "Invalid color id"),
+            CheckTrustLayerRefundError::FailedToParseAddress { address: e } => {
+                write!(f, "Failed to parse address: {}", e)
+            }
+            CheckTrustLayerRefundError::WrongNetworkAddress { address: e } => {
+                write!(f, "Wrong network address: {}", e)
+            }
+            CheckTrustLayerRefundError::InvalidRefundAddress { address: e } => {
+                write!(f, "The refund address has the other color: {}", e)
+            }
         }
     }
 }
 
 impl std::error::Error for CheckTrustLayerRefundError {}
 
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum RefundTrustLayerTransferError {
+    FailedToParseTxid { txid: String },
+    FailedToParseAddress { address: String },
+    WrongNetworkAddress { address: String },
+    InvalidRefundAddress { address: String },
+    ContractNotSpecified,
+    UnknownContract,
+    ContractMismatch,
+    ContractNotPayable,
+    ContractError { cause_description: String },
+    NoRefundableOutput,
+    InsufficientFund,
+    EsploraClient { cause_description: String },
+    ElectrumClient { cause_description: String },
+    FailedToCreateTransaction { cause_description: String },
+    WalletDBError { cause_description: String },
+}
+
+impl Display for RefundTrustLayerTransferError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefundTrustLayerTransferError::FailedToParseTxid { txid: e } => {
+                write!(f, "Failed to parse txid: {}", e)
+            }
+            RefundTrustLayerTransferError::FailedToParseAddress { address: e } => {
+                write!(f, "Failed to parse address: {}", e)
+            }
+            RefundTrustLayerTransferError::WrongNetworkAddress { address: e } => {
+                write!(f, "Wrong network address: {}", e)
+            }
+            RefundTrustLayerTransferError::InvalidRefundAddress { address: e } => {
+                write!(f, "The refund address has the other color: {}", e)
+            }
+            RefundTrustLayerTransferError::ContractNotSpecified => {
+                write!(f, "Contract id or pay to contract address is required")
+            }
+            RefundTrustLayerTransferError::UnknownContract => write!(f, "Unknown contract"),
+            RefundTrustLayerTransferError::ContractMismatch => {
+                write!(f, "Pay to contract address does not match the contract")
+            }
+            RefundTrustLayerTransferError::ContractNotPayable => {
+                write!(f, "Contract is not payable")
+            }
+            RefundTrustLayerTransferError::ContractError {
+                cause_description: e,
+            } => write!(f, "Contract error: {}", e),
+            RefundTrustLayerTransferError::NoRefundableOutput => {
+                write!(f, "No refundable output in the transaction")
+            }
+            RefundTrustLayerTransferError::InsufficientFund => write!(f, "Insufficient fund"),
+            RefundTrustLayerTransferError::EsploraClient {
+                cause_description: e,
+            } => write!(f, "Esplora client error: {}", e),
+            RefundTrustLayerTransferError::ElectrumClient {
+                cause_description: e,
+            } => write!(f, "Electrum client error: {}", e),
+            RefundTrustLayerTransferError::FailedToCreateTransaction {
+                cause_description: e,
+            } => write!(f, "Failed to create transaction: {}", e),
+            RefundTrustLayerTransferError::WalletDBError {
+                cause_description: e,
+            } => write!(f, "Wallet DB error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RefundTrustLayerTransferError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum StartNotificationListenerError {
+    UnsupportedBackend,
+    AlreadyStarted,
+    ElectrumClientError { cause_description: String },
+}
+
+impl Display for StartNotificationListenerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartNotificationListenerError::UnsupportedBackend => {
+                write!(
+                    f,
+                    "Notification listener is only supported by electrum backend"
+                )
+            }
+            StartNotificationListenerError::AlreadyStarted => {
+                write!(f, "Notification listener is already started")
+            }
+            StartNotificationListenerError::ElectrumClientError {
+                cause_description: e,
+            } => {
+                write!(f, "Electrum client error: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StartNotificationListenerError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum StartBackgroundSyncError {
+    InvalidInterval,
+    AlreadyStarted,
+}
+
+impl Display for StartBackgroundSyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartBackgroundSyncError::InvalidInterval => {
+                write!(f, "Sync interval must be greater than 0")
+            }
+            StartBackgroundSyncError::AlreadyStarted => {
+                write!(f, "Background sync is already started")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StartBackgroundSyncError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum GetSyncStatusError {
+    WalletDBError { cause_description: String },
+}
+
+impl Display for GetSyncStatusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GetSyncStatusError::WalletDBError {
+                cause_description: e,
+            } => write!(f, "Wallet db error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GetSyncStatusError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum GetBackendTipError {
+    EsploraClientError { cause_description: String },
+    ElectrumClientError { cause_description: String },
+}
+
+impl Display for GetBackendTipError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GetBackendTipError::EsploraClientError {
+                cause_description: e,
+            } => write!(f, "Esplora client error: {}", e),
+            GetBackendTipError::ElectrumClientError {
+                cause_description: e,
+            } => write!(f, "Electrum client error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GetBackendTipError {}
+
 impl HdWallet {
     pub fn new(config: Arc<Config>) -> Result<Self, NewError> {
         let Config {
@@ -574,6 +1482,8 @@ impl HdWallet {
             master_key_path,
             master_key,
             db_file_path,
+            esplora_headers,
+            esplora_token_provider,
         } = config.as_ref();
         let network: tapyrus::network::Network = network_mode.clone().into();
 
@@ -599,6 +1509,8 @@ impl HdWallet {
                 url: esplora_url.clone().unwrap(),
                 user: esplora_user.clone(),
                 password: esplora_password.clone(),
+                headers: esplora_headers.clone().unwrap_or_default(),
+                token_provider: esplora_token_provider.clone(),
             }
         } else {
             // Validate electrum config
@@ -652,6 +1564,9 @@ impl HdWallet {
         let db = Store::new(conn).map_err(|e| NewError::LoadWalletDBError {
             cause_description: e.to_string(),
         })?;
+        let metadata = Metadata::open(&db_path).map_err(|e| NewError::LoadWalletDBError {
+            cause_description: e.to_string(),
+        })?;
 
         let genesis_hash =
             BlockHash::from_str(genesis_hash).map_err(|_| NewError::ParseGenesisHashError)?;
@@ -695,30 +1610,96 @@ impl HdWallet {
             network,
             wallet: Mutex::new(wallet),
             backend,
+            metadata,
+            sync_lock: Mutex::new(()),
+            notification_worker: Mutex::new(None),
+            sync_worker: Mutex::new(None),
+            event_listener: Mutex::new(None),
+            contract_registry: Mutex::new(None),
         })
     }
 
-    pub fn sync(&self) -> Result<(), SyncError> {
-        let mut wallet = self.get_wallet();
+    pub fn sync(&self) -> Result<SyncResult, SyncError> {
+        let (payment_bases, candidates) = self.find_contract_candidates();
+        let _sync_lock = self.sync_lock.lock().expect("Failed to lock sync");
+        let discovered_contracts = self.discover_contracts(&payment_bases, candidates);
+        let mut result = self.sync_with_request(|wallet| wallet.start_sync_with_revealed_spks())?;
+        self.record_sync(false);
+        result.discovered_contracts = discovered_contracts;
+        Ok(result)
+    }
 
-        match &self.backend {
+    pub fn sync_addresses(&self, addresses: Vec<String>) -> Result<SyncResult, SyncAddressesError> {
+        let scripts = addresses
+            .iter()
+            .map(|address| {
+                let script = Address::from_str(address)
+                    .map_err(|_| SyncAddressesError::FailedToParseAddress {
+                        address: address.clone(),
+                    })?
+                    .require_network(self.network)
+                    .map_err(|_| SyncAddressesError::WrongNetworkAddress {
+                        address: address.clone(),
+                    })?
+                    .script_pubkey();
+                Ok(script)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let _sync_lock = self.sync_lock.lock().expect("Failed to lock sync");
+        // The transactions of the other scripts must not be applied to the wallet.
+        {
+            let wallet = self.get_wallet();
+            let contract_scripts = self
+                .stored_contracts(&wallet)
+                .map_err(|e| SyncAddressesError::UpdateWalletError {
+                    cause_description: e.to_string(),
+                })?
+                .iter()
+                .filter_map(|contract| Self::contract_script(&wallet, contract).ok())
+                .collect::<Vec<_>>();
+            for (address, script) in addresses.iter().zip(&scripts) {
+                let script = script.remove_color();
+                if !wallet.is_mine(script.as_script()) && !contract_scripts.contains(&script) {
+                    return Err(SyncAddressesError::UnknownAddress {
+                        address: address.clone(),
+                    });
+                }
+            }
+        }
+
+        let result = self.sync_with_request(move |wallet| {
+            SyncRequest::from_chain_tip(wallet.latest_checkpoint()).chain_spks(scripts)
+        })?;
+        Ok(result)
+    }
+
+    /// The caller must hold the sync lock.
+    fn sync_with_request<F>(&self, build_request: F) -> Result<SyncResult, SyncError>
+    where
+        F: FnOnce(&Wallet) -> SyncRequest,
+    {
+        // The wallet is locked only while building the request and applying the update so that
+        // other calls are not blocked during the network requests.
+        let started = Instant::now();
+
+        let mut result = match &self.backend {
             BackendClient::Esplora {
                 url,
                 user,
                 password,
+                headers,
+                token_provider,
             } => {
-                let client = Self::create_esplora_client(url, user, password);
-                let request = wallet.start_sync_with_revealed_spks();
+                let client =
+                    Self::create_esplora_client(url, user, password, headers, token_provider);
+                let request = build_request(&self.get_wallet());
                 let update = client.sync(request, SYNC_PARALLEL_REQUESTS).map_err(|e| {
                     SyncError::EsploraClientError {
                         cause_description: e.to_string(),
                     }
                 })?;
-                wallet
-                    .apply_update(update)
-                    .map_err(|e| SyncError::UpdateWalletError {
-                        cause_description: e.to_string(),
-                    })?;
+                self.apply_update(update)?
             }
             BackendClient::Electrum { url } => {
                 let client = Self::create_electrum_client(url).map_err(|e| {
@@ -726,7 +1707,7 @@ impl HdWallet {
                         cause_description: e.to_string(),
                     }
                 })?;
-                let request = wallet.start_sync_with_revealed_spks();
+                let request = build_request(&self.get_wallet());
                 let electrum_result =
                     client
                         .sync(request, SYNC_PARALLEL_REQUESTS, true)
@@ -738,37 +1719,36 @@ impl HdWallet {
                     .map_err(|e| SyncError::ElectrumClientError {
                         cause_description: e.to_string(),
                     })?;
-                wallet
-                    .apply_update(update)
-                    .map_err(|e| SyncError::UpdateWalletError {
-                        cause_description: e.to_string(),
-                    })?;
+                self.apply_update(update)?
             }
-        }
-        Ok(())
+        };
+        result.elapsed_ms = started.elapsed().as_millis() as u64;
+        Ok(result)
     }
 
-    pub fn full_sync(&self) -> Result<(), SyncError> {
-        let mut wallet = self.get_wallet();
+    pub fn full_sync(&self) -> Result<SyncResult, SyncError> {
+        let (payment_bases, candidates) = self.find_contract_candidates();
+        let _sync_lock = self.sync_lock.lock().expect("Failed to lock sync");
+        let discovered_contracts = self.discover_contracts(&payment_bases, candidates);
+        let started = Instant::now();
 
-        match &self.backend {
+        let mut result = match &self.backend {
             BackendClient::Esplora {
                 url,
                 user,
                 password,
+                headers,
+                token_provider,
             } => {
-                let client = Self::create_esplora_client(url, user, password);
-                let request = wallet.start_full_scan();
+                let client =
+                    Self::create_esplora_client(url, user, password, headers, token_provider);
+                let request = self.get_wallet().start_full_scan();
                 let update = client
                     .full_scan(request, STOP_GAP, SYNC_PARALLEL_REQUESTS)
                     .map_err(|e| SyncError::EsploraClientError {
                         cause_description: e.to_string(),
                     })?;
-                wallet
-                    .apply_update(update)
-                    .map_err(|e| SyncError::UpdateWalletError {
-                        cause_description: e.to_string(),
-                    })?;
+                self.apply_update(update)?
             }
             BackendClient::Electrum { url } => {
                 let client = Self::create_electrum_client(url).map_err(|e| {
@@ -776,7 +1756,7 @@ impl HdWallet {
                         cause_description: e.to_string(),
                     }
                 })?;
-                let request = wallet.start_full_scan();
+                let request = self.get_wallet().start_full_scan();
                 let electrum_result = client
                     .full_scan(request, STOP_GAP, SYNC_PARALLEL_REQUESTS, true)
                     .map_err(|e| SyncError::ElectrumClientError {
@@ -787,34 +1767,324 @@ impl HdWallet {
                     .map_err(|e| SyncError::ElectrumClientError {
                         cause_description: e.to_string(),
                     })?;
-                wallet
-                    .apply_update(update)
-                    .map_err(|e| SyncError::UpdateWalletError {
-                        cause_description: e.to_string(),
-                    })?;
+                self.apply_update(update)?
             }
-        }
-        Ok(())
+        };
+        self.record_sync(true);
+        result.elapsed_ms = started.elapsed().as_millis() as u64;
+        result.discovered_contracts = discovered_contracts;
+        Ok(result)
     }
 
-    fn create_esplora_client(
-        url: &str,
-        user: &Option<String>,
-        password: &Option<String>,
-    ) -> esplora_client::BlockingClient {
-        let mut builder = esplora_client::Builder::new(url);
-
-        // Set basic authentication if user and password are provided
-        if let (Some(user), Some(password)) = (user, password) {
-            use base64::prelude::*;
+    /// Ask the contract registry for the contracts of the payment bases of the wallet.
+    /// Return the payment bases and the contracts provided by the registry.
+    /// The registry is called without the sync lock, because it may take a while and block the
+    /// background sync, or call back into the wallet.
+    fn find_contract_candidates(&self) -> (Vec<PublicKey>, Vec<Contract>) {
+        let registry = match self
+            .contract_registry
+            .lock()
+            .expect("Failed to lock contract registry")
+            .clone()
+        {
+            Some(registry) => registry,
+            None => return (Vec::new(), Vec::new()),
+        };
 
-            let credentials = format!("{}:{}", user, password);
-            let encoded = BASE64_STANDARD.encode(credentials.as_bytes());
-            let auth_haeder_value = format!("Basic {}", encoded);
-            builder = builder.header("Authorization", auth_haeder_value.as_str());
+        let payment_bases: Vec<PublicKey> = {
+            let _sync_lock = self.sync_lock.lock().expect("Failed to lock sync");
+            let wallet = self.get_wallet();
+            wallet
+                .spk_index()
+                .revealed_keychain_spks(&KeychainKind::External)
+                .map(|(index, _)| Self::derive_public_key(&wallet, KeychainKind::External, index))
+                .collect()
+        };
+        if payment_bases.is_empty() {
+            return (payment_bases, Vec::new());
         }
 
-        builder.build_blocking()
+        let candidates =
+            registry.find_contracts(payment_bases.iter().map(|pk| pk.to_string()).collect());
+        (payment_bases, candidates)
+    }
+
+    /// Store the candidate contracts provided by the contract registry for the payment bases, so
+    /// that the following sync finds the payments to the contracts.
+    /// Only the contracts whose pay to contract address has received the payments are stored.
+    /// The contract which fails to be discovered is skipped not to fail the sync.
+    /// The caller must hold the sync lock.
+    fn discover_contracts(
+        &self,
+        payment_bases: &[PublicKey],
+        candidates: Vec<Contract>,
+    ) -> Vec<String> {
+        if candidates.is_empty() {
+            return Vec::new();
+        }
+        let client = match self.refund_client() {
+            Ok(client) => client,
+            Err(e) => {
+                log::warn!("Failed to discover the contracts: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let mut discovered = Vec::new();
+        for candidate in candidates {
+            match self.discover_contract(&client, &candidate, payment_bases) {
+                Ok(true) => discovered.push(candidate.contract_id),
+                Ok(false) => {}
+                Err(e) => log::warn!(
+                    "Failed to discover the contract {}: {}",
+                    candidate.contract_id,
+                    e
+                ),
+            }
+        }
+        discovered
+    }
+
+    /// Store the candidate contract if it is for the wallet and paid.
+    /// Return whether the contract is stored.
+    fn discover_contract(
+        &self,
+        client: &RefundClient,
+        candidate: &Contract,
+        payment_bases: &[PublicKey],
+    ) -> Result<bool, String> {
+        let payment_base =
+            PublicKey::from_str(&candidate.payment_base).map_err(|e| e.to_string())?;
+        if !payment_bases.contains(&payment_base) {
+            return Ok(false);
+        }
+        let encoding = candidate.encoding.unwrap_or(ContractEncoding::Utf8);
+        let content = encoding.decode(&candidate.contract)?;
+        let script = {
+            let wallet = self.get_wallet();
+            let stored = self
+                .stored_contract(&wallet, &candidate.contract_id)
+                .map_err(|e| e.to_string())?;
+            if stored.is_some() {
+                return Ok(false);
+            }
+            wallet
+                .create_pay_to_contract_address(&payment_base, content.clone(), None)
+                .map_err(|e| e.to_string())?
+                .script_pubkey()
+        };
+
+        // Look up the payments without locking the wallet.
+        if !client.has_history(&script).map_err(|e| e.to_string())? {
+            return Ok(false);
+        }
+        let mut wallet = self.get_wallet();
+        self.store_contract_bytes(
+            &mut wallet,
+            candidate.contract_id.clone(),
+            content,
+            payment_base,
+            candidate.payable,
+            encoding,
+        )?;
+        Ok(true)
+    }
+
+    /// The update is already applied to the wallet, so the failure to record the sync status
+    /// does not fail the sync.
+    fn record_sync(&self, full_scan: bool) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut result = self.metadata.set(LAST_SYNC_TIME_KEY, &now.to_string());
+        if full_scan {
+            result = result.and_then(|_| self.metadata.set(FULL_SCAN_COMPLETED_KEY, "true"));
+        }
+        if let Err(e) = result {
+            log::warn!("Failed to record the sync status: {}", e);
+        }
+    }
+
+    pub fn sync_status(&self) -> Result<SyncStatus, GetSyncStatusError> {
+        let map_err = |e: rusqlite::Error| GetSyncStatusError::WalletDBError {
+            cause_description: e.to_string(),
+        };
+        let last_sync_time = self
+            .metadata
+            .get(LAST_SYNC_TIME_KEY)
+            .map_err(map_err)?
+            .and_then(|time| time.parse::<u64>().ok());
+        let full_scan_completed = self
+            .metadata
+            .get(FULL_SCAN_COMPLETED_KEY)
+            .map_err(map_err)?
+            .is_some();
+
+        let tip = self.get_wallet().latest_checkpoint();
+        Ok(SyncStatus {
+            local_tip: ChainTip {
+                height: tip.height(),
+                block_hash: tip.hash().to_string(),
+            },
+            last_sync_time,
+            full_scan_completed,
+        })
+    }
+
+    pub fn get_backend_tip(&self) -> Result<ChainTip, GetBackendTipError> {
+        match &self.backend {
+            BackendClient::Esplora {
+                url,
+                user,
+                password,
+                headers,
+                token_provider,
+            } => {
+                let client =
+                    Self::create_esplora_client(url, user, password, headers, token_provider);
+                let map_err = |e: esplora_client::Error| GetBackendTipError::EsploraClientError {
+                    cause_description: e.to_string(),
+                };
+                let height = client.get_height().map_err(map_err)?;
+                let block_hash = client.get_block_hash(height).map_err(map_err)?;
+                Ok(ChainTip {
+                    height,
+                    block_hash: block_hash.to_string(),
+                })
+            }
+            BackendClient::Electrum { url } => {
+                let client = Self::create_electrum_client(url).map_err(|e| {
+                    GetBackendTipError::ElectrumClientError {
+                        cause_description: e.to_string(),
+                    }
+                })?;
+                let header = client.inner.block_headers_subscribe().map_err(|e| {
+                    GetBackendTipError::ElectrumClientError {
+                        cause_description: e.to_string(),
+                    }
+                })?;
+                Ok(ChainTip {
+                    height: header.height as u32,
+                    block_hash: header.header.block_hash().to_string(),
+                })
+            }
+        }
+    }
+
+    /// Apply the update to the wallet and notify the changes to the event listener.
+    fn apply_update(&self, update: impl Into<Update>) -> Result<SyncResult, SyncError> {
+        let (result, events) = {
+            let mut wallet = self.get_wallet();
+            let before = WalletSnapshot::new(&wallet);
+            wallet
+                .apply_update(update)
+                .map_err(|e| SyncError::UpdateWalletError {
+                    cause_description: e.to_string(),
+                })?;
+            let after = WalletSnapshot::new(&wallet);
+
+            let result = SyncResult {
+                new_transactions: after
+                    .txs
+                    .keys()
+                    .filter(|txid| !before.txs.contains_key(*txid))
+                    .count() as u32,
+                updated_transactions: before
+                    .txs
+                    .iter()
+                    .filter(|(txid, height)| after.txs.get(*txid) != Some(*height))
+                    .count() as u32,
+                newly_used_addresses: after.used.difference(&before.used).count() as u32,
+                last_revealed_external_index: wallet.derivation_index(KeychainKind::External),
+                last_revealed_internal_index: wallet.derivation_index(KeychainKind::Internal),
+                tip_height: wallet.latest_checkpoint().height(),
+                elapsed_ms: 0,
+                reorg: before.reorg(&after),
+                discovered_contracts: Vec::new(),
+            };
+            (result, before.events(&after))
+        };
+
+        // Call the listener after the wallet is unlocked so that the listener can use the wallet.
+        let listener = self
+            .event_listener
+            .lock()
+            .expect("Failed to lock event listener")
+            .clone();
+        if let Some(listener) = listener {
+            for event in events {
+                listener.on_event(event);
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn set_event_listener(&self, listener: Option<Box<dyn WalletEventListener>>) {
+        *self
+            .event_listener
+            .lock()
+            .expect("Failed to lock event listener") = listener.map(Arc::from);
+    }
+
+    pub fn set_contract_registry(&self, registry: Option<Box<dyn ContractRegistry>>) {
+        *self
+            .contract_registry
+            .lock()
+            .expect("Failed to lock contract registry") = registry.map(Arc::from);
+    }
+
+    fn create_esplora_client(
+        url: &str,
+        user: &Option<String>,
+        password: &Option<String>,
+        headers: &HashMap<String, String>,
+        token_provider: &Option<Arc<dyn EsploraTokenProvider>>,
+    ) -> esplora_client::BlockingClient {
+        let mut builder = esplora_client::Builder::new(url);
+        for (key, value) in Self::esplora_headers(user, password, headers, token_provider) {
+            builder = builder.header(&key, &value);
+        }
+        builder.build_blocking()
+    }
+
+    /// Build the headers of the esplora client created for an operation.
+    /// Custom headers override the basic authentication, and the bearer token from the token
+    /// provider overrides both.
+    fn esplora_headers(
+        user: &Option<String>,
+        password: &Option<String>,
+        headers: &HashMap<String, String>,
+        token_provider: &Option<Arc<dyn EsploraTokenProvider>>,
+    ) -> HashMap<String, String> {
+        let mut result = HashMap::new();
+
+        // Set basic authentication if user and password are provided
+        if let (Some(user), Some(password)) = (user, password) {
+            use base64::prelude::*;
+
+            let credentials = format!("{}:{}", user, password);
+            let encoded = BASE64_STANDARD.encode(credentials.as_bytes());
+            result.insert("Authorization".to_string(), format!("Basic {}", encoded));
+        }
+
+        for (key, value) in headers {
+            // Header names are case-insensitive, so drop the entry that would be duplicated
+            result.retain(|k, _| !k.eq_ignore_ascii_case(key));
+            result.insert(key.clone(), value.clone());
+        }
+
+        // The token is read when the client is built for each operation, so that the token
+        // refreshed between the operations is used
+        if let Some(token_provider) = token_provider {
+            result.retain(|k, _| !k.eq_ignore_ascii_case("Authorization"));
+            result.insert(
+                "Authorization".to_string(),
+                format!("Bearer {}", token_provider.token()),
+            );
+        }
+
+        result
     }
 
     fn create_electrum_client(
@@ -832,37 +2102,373 @@ impl HdWallet {
         &self,
         color_id: Option<String>,
     ) -> Result<GetNewAddressResult, GetNewAddressError> {
+        let color_id = match color_id {
+            Some(id) => Some(
+                ColorIdentifier::from_str(&id).map_err(|_| GetNewAddressError::InvalidColorId)?,
+            ),
+            None => None,
+        };
+
         let mut wallet = self.get_wallet();
         let keychain = KeychainKind::External;
         let address_info = wallet.reveal_next_address(keychain).unwrap();
+        Ok(self.address_result(&wallet, keychain, address_info, color_id))
+    }
 
-        let descriptor = wallet.get_descriptor_for_keychain(keychain);
-        let secp = secp256k1::Secp256k1::verification_only();
-        let derived_descriptor = descriptor
-            .derived_descriptor(&secp, address_info.index)
-            .unwrap();
-        let public_key = match derived_descriptor {
-            Descriptor::Pkh(a) => a.into_inner(),
-            _ => {
-                panic!("get_new_address() doesn't support Bare and Sh descriptor")
+    pub fn get_unused_address(
+        &self,
+        color_id: Option<String>,
+    ) -> Result<GetNewAddressResult, GetNewAddressError> {
+        let color_id = match color_id {
+            Some(id) => Some(
+                ColorIdentifier::from_str(&id).map_err(|_| GetNewAddressError::InvalidColorId)?,
+            ),
+            None => None,
+        };
+
+        let mut wallet = self.get_wallet();
+        let keychain = KeychainKind::External;
+        let address_info = wallet.next_unused_address(keychain).unwrap();
+        Ok(self.address_result(&wallet, keychain, address_info, color_id))
+    }
+
+    pub fn get_change_address(
+        &self,
+        color_id: Option<String>,
+    ) -> Result<GetNewAddressResult, GetNewAddressError> {
+        let color_id = match color_id {
+            Some(id) => Some(
+                ColorIdentifier::from_str(&id).map_err(|_| GetNewAddressError::InvalidColorId)?,
+            ),
+            None => None,
+        };
+
+        let mut wallet = self.get_wallet();
+        let keychain = KeychainKind::Internal;
+        let address_info = wallet.next_unused_address(keychain).unwrap();
+        Ok(self.address_result(&wallet, keychain, address_info, color_id))
+    }
+
+    pub fn mark_used(&self, keychain: Keychain, index: u32) -> bool {
+        self.get_wallet().mark_used(keychain.into(), index)
+    }
+
+    pub fn unmark_used(&self, keychain: Keychain, index: u32) -> bool {
+        self.get_wallet().unmark_used(keychain.into(), index)
+    }
+
+    pub fn peek_address(
+        &self,
+        keychain: Keychain,
+        index: u32,
+        color_id: Option<String>,
+    ) -> Result<GetNewAddressResult, PeekAddressError> {
+        // Hardened index can not be derived from the public key
+        if ChildNumber::from_normal_idx(index).is_err() {
+            return Err(PeekAddressError::InvalidIndex);
+        }
+        let color_id = match color_id {
+            Some(id) => {
+                Some(ColorIdentifier::from_str(&id).map_err(|_| PeekAddressError::InvalidColorId)?)
             }
+            None => None,
         };
 
-        let address = if let Some(color_id) = color_id {
-            let color_id = ColorIdentifier::from_str(&color_id)
-                .map_err(|_| GetNewAddressError::InvalidColorId)?;
-            let script = address_info.script_pubkey().add_color(color_id).unwrap();
-            Address::from_script(&script, self.network).unwrap()
-        } else {
-            address_info.address
+        let wallet = self.get_wallet();
+        let keychain = keychain.into();
+        let address_info = wallet.peek_address(keychain, index);
+        Ok(self.address_result(&wallet, keychain, address_info, color_id))
+    }
+
+    /// Build the result with the public key of the address, and add the color to the address if specified.
+    fn address_result(
+        &self,
+        wallet: &Wallet,
+        keychain: KeychainKind,
+        address_info: tdk_wallet::wallet::AddressInfo,
+        color_id: Option<ColorIdentifier>,
+    ) -> GetNewAddressResult {
+        let public_key = Self::derive_public_key(wallet, keychain, address_info.index);
+        let address = match color_id {
+            Some(color_id) => {
+                let script = address_info.script_pubkey().add_color(color_id).unwrap();
+                Address::from_script(&script, self.network).unwrap()
+            }
+            None => address_info.address,
         };
 
-        Ok(GetNewAddressResult {
+        GetNewAddressResult {
             address: address.to_string(),
             public_key: public_key.to_string(),
+        }
+    }
+
+    pub fn list_addresses(&self, keychain: Keychain) -> Vec<AddressInfo> {
+        let wallet = self.get_wallet();
+        let keychain_kind: KeychainKind = keychain.into();
+
+        // Sum up the received amount for each color by the derivation index
+        let mut received: BTreeMap<u32, BTreeMap<Option<String>, u64>> = BTreeMap::new();
+        for output in wallet.list_output() {
+            if output.keychain != keychain_kind {
+                continue;
+            }
+            let color_id = output
+                .txout
+                .script_pubkey
+                .color_id()
+                .map(|id| id.to_string());
+            *received
+                .entry(output.derivation_index)
+                .or_default()
+                .entry(color_id)
+                .or_default() += output.txout.value.to_tap();
+        }
+
+        wallet
+            .spk_index()
+            .revealed_keychain_spks(&keychain_kind)
+            .map(|(index, script)| {
+                let public_key = Self::derive_public_key(&wallet, keychain_kind, index);
+                let amounts = received.remove(&index).unwrap_or_default();
+                AddressInfo {
+                    keychain,
+                    index,
+                    address: Address::from_script(script, self.network)
+                        .unwrap()
+                        .to_string(),
+                    public_key: public_key.to_string(),
+                    used: !amounts.is_empty(),
+                    received: amounts
+                        .into_iter()
+                        .map(|(color_id, amount)| ColoredAmount { color_id, amount })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn parse_address(&self, address: String) -> Result<AddressDetails, ParseAddressError> {
+        let unchecked =
+            Address::from_str(&address).map_err(|_| ParseAddressError::FailedToParseAddress {
+                address: address.clone(),
+            })?;
+        let network =
+            address_network(&unchecked).ok_or_else(|| ParseAddressError::FailedToParseAddress {
+                address: address.clone(),
+            })?;
+        let script = unchecked.assume_checked().script_pubkey();
+
+        let color_id = script.color_id();
+        let base_script = script.remove_color();
+        let address_type = match (
+            color_id.is_some(),
+            base_script.is_p2pkh(),
+            base_script.is_p2sh(),
+        ) {
+            (false, true, _) => AddressType::P2pkh,
+            (false, _, true) => AddressType::P2sh,
+            (true, true, _) => AddressType::Cp2pkh,
+            (true, _, true) => AddressType::Cp2sh,
+            _ => return Err(ParseAddressError::UnsupportedAddressType { address }),
+        };
+
+        // The address for the other network never belongs to this wallet
+        let is_mine = network == self.network && self.get_wallet().is_mine(&script.remove_color());
+
+        Ok(AddressDetails {
+            network: network.into(),
+            address_type,
+            color_id: color_id.map(|id| id.to_string()),
+            script_pubkey: script.to_hex_string(),
+            is_mine,
         })
     }
 
+    pub fn encode_payment_uri(&self, request: PaymentRequest) -> Result<String, PaymentUriError> {
+        // The color id of the colored address is used if the request does not have color_id.
+        let color_id = self
+            .validate_payment_request(&request)?
+            .map(|id| id.to_string());
+
+        let mut params = Vec::new();
+        if let Some(amount) = request.amount {
+            let amount = if color_id.is_some() {
+                amount.to_string()
+            } else {
+                format_tpc_amount(amount)
+            };
+            params.push(("amount", amount));
+        }
+        let optional_params = [
+            ("colorid", &color_id),
+            ("label", &request.label),
+            ("message", &request.message),
+            ("paymentbase", &request.payment_base),
+            ("contract", &request.contract),
+            (
+                "contractencoding",
+                &request.contract_encoding.map(|e| e.as_str().to_string()),
+            ),
+        ];
+        for (name, value) in optional_params {
+            if let Some(value) = value {
+                params.push((name, percent_encode(value)));
+            }
+        }
+
+        let mut uri = format!("{}:{}", PAYMENT_URI_SCHEME, request.address);
+        for (i, (name, value)) in params.iter().enumerate() {
+            uri.push(if i == 0 { '?' } else { '&' });
+            uri.push_str(name);
+            uri.push('=');
+            uri.push_str(value);
+        }
+        Ok(uri)
+    }
+
+    pub fn decode_payment_uri(&self, uri: String) -> Result<PaymentRequest, PaymentUriError> {
+        let invalid_uri = |cause: &str| PaymentUriError::InvalidUri {
+            cause_description: cause.to_string(),
+        };
+
+        let (scheme, rest) = uri
+            .split_once(':')
+            .ok_or_else(|| invalid_uri("no scheme"))?;
+        if !scheme.eq_ignore_ascii_case(PAYMENT_URI_SCHEME) {
+            return Err(invalid_uri("unknown scheme"));
+        }
+        let (address, query) = match rest.split_once('?') {
+            Some((address, query)) => (address, Some(query)),
+            None => (rest, None),
+        };
+
+        let mut params: HashMap<String, String> = HashMap::new();
+        for param in query.into_iter().flat_map(|q| q.split('&')) {
+            if param.is_empty() {
+                continue;
+            }
+            let (name, value) = param
+                .split_once('=')
+                .ok_or_else(|| invalid_uri("parameter without value"))?;
+            let value = percent_decode(value).ok_or_else(|| invalid_uri("malformed encoding"))?;
+            if params.insert(name.to_lowercase(), value).is_some() {
+                return Err(invalid_uri("duplicated parameter"));
+            }
+        }
+
+        let mut request = PaymentRequest {
+            address: address.to_string(),
+            amount: None,
+            color_id: params.remove("colorid"),
+            label: params.remove("label"),
+            message: params.remove("message"),
+            payment_base: params.remove("paymentbase"),
+            contract: params.remove("contract"),
+            contract_encoding: params
+                .remove("contractencoding")
+                .map(|e| ContractEncoding::from_str(&e))
+                .transpose()
+                .map_err(|e| PaymentUriError::InvalidContract {
+                    cause_description: e,
+                })?,
+        };
+        // Follow BIP21: unknown parameters prefixed with "req-" must be understood
+        if let Some(name) = params.keys().find(|name| name.starts_with("req-")) {
+            return Err(PaymentUriError::UnknownRequiredParameter { name: name.clone() });
+        }
+
+        // The color id of the colored address is used if the uri does not have colorid.
+        let color_id = self.validate_payment_request(&request)?;
+        request.color_id = color_id.map(|id| id.to_string());
+
+        if let Some(amount) = params.remove("amount") {
+            let parsed = if request.color_id.is_some() {
+                amount.parse::<u64>().ok()
+            } else {
+                parse_tpc_amount(&amount)
+            };
+            request.amount = Some(parsed.ok_or(PaymentUriError::InvalidAmount { amount })?);
+        }
+        Ok(request)
+    }
+
+    /// Validate the payment request and return the color id of the payment.
+    fn validate_payment_request(
+        &self,
+        request: &PaymentRequest,
+    ) -> Result<Option<ColorIdentifier>, PaymentUriError> {
+        let script = Address::from_str(&request.address)
+            .map_err(|_| PaymentUriError::FailedToParseAddress {
+                address: request.address.clone(),
+            })?
+            .require_network(self.network)
+            .map_err(|_| PaymentUriError::WrongNetworkAddress {
+                address: request.address.clone(),
+            })?
+            .script_pubkey();
+
+        let color_id = match &request.color_id {
+            Some(id) => {
+                let id =
+                    ColorIdentifier::from_str(id).map_err(|_| PaymentUriError::InvalidColorId)?;
+                if script.color_id().is_some_and(|c| c != id) {
+                    return Err(PaymentUriError::ColorIdMismatch);
+                }
+                Some(id)
+            }
+            None => script.color_id(),
+        };
+
+        match (&request.payment_base, &request.contract) {
+            (None, None) if request.contract_encoding.is_none() => {}
+            (Some(payment_base), Some(contract)) => {
+                let payment_base = PublicKey::from_str(payment_base).map_err(|_| {
+                    PaymentUriError::InvalidContract {
+                        cause_description: "invalid payment base".to_string(),
+                    }
+                })?;
+                let contract = request
+                    .contract_encoding
+                    .unwrap_or(ContractEncoding::Utf8)
+                    .decode(contract)
+                    .map_err(|e| PaymentUriError::InvalidContract {
+                        cause_description: e,
+                    })?;
+                let p2c_address = self
+                    .get_wallet()
+                    .create_pay_to_contract_address(&payment_base, contract, color_id.clone())
+                    .map_err(|e| PaymentUriError::InvalidContract {
+                        cause_description: e.to_string(),
+                    })?;
+                if p2c_address.script_pubkey() != script {
+                    return Err(PaymentUriError::InvalidContract {
+                        cause_description: "address does not match the contract".to_string(),
+                    });
+                }
+            }
+            _ => {
+                return Err(PaymentUriError::InvalidContract {
+                    cause_description: "both payment base and contract are required".to_string(),
+                })
+            }
+        }
+        Ok(color_id)
+    }
+
+    /// Derive the public key at the index of the keychain.
+    fn derive_public_key(wallet: &Wallet, keychain: KeychainKind, index: u32) -> PublicKey {
+        let descriptor = wallet.get_descriptor_for_keychain(keychain);
+        let secp = secp256k1::Secp256k1::verification_only();
+        let derived_descriptor = descriptor.derived_descriptor(&secp, index).unwrap();
+        match derived_descriptor {
+            Descriptor::Pkh(a) => a.into_inner(),
+            _ => {
+                panic!("The wallet doesn't support Bare and Sh descriptor")
+            }
+        }
+    }
+
     pub fn balance(&self, color_id: Option<String>) -> Result<u64, BalanceError> {
         let color_id = if let Some(color_id) = color_id {
             ColorIdentifier::from_str(&color_id).map_err(|_| BalanceError::InvalidColorId)?
@@ -878,9 +2484,63 @@ impl HdWallet {
         params: Vec<TransferParams>,
         utxos: Vec<TxOut>,
     ) -> Result<String, TransferError> {
+        self.transfer_with_options(params, utxos, TransferOptions::default())
+    }
+
+    pub fn transfer_with_options(
+        &self,
+        params: Vec<TransferParams>,
+        utxos: Vec<TxOut>,
+        options: TransferOptions,
+    ) -> Result<String, TransferError> {
+        let parse_change_address = |address: &String| -> Result<ScriptBuf, TransferError> {
+            let script = Address::from_str(address)
+                .map_err(|_| TransferError::FailedToParseAddress {
+                    address: address.clone(),
+                })?
+                .require_network(self.network)
+                .map_err(|_| TransferError::WrongNetworkAddress {
+                    address: address.clone(),
+                })?
+                .script_pubkey();
+            // The change address is colored with the color of each change output, so the colored
+            // address is not accepted.
+            if script.is_colored() {
+                return Err(TransferError::InvalidChangeAddress {
+                    address: address.clone(),
+                });
+            }
+            Ok(script)
+        };
+        let change_script = options
+            .change_address
+            .as_ref()
+            .map(parse_change_address)
+            .transpose()?;
+        let colored_change_script = match &options.colored_change_address {
+            Some(address) => {
+                let script = parse_change_address(address)?;
+                // The colored change output is replaced after the fee is calculated, so the
+                // script must have the same size as the P2PKH script of the wallet.
+                if !script.is_p2pkh() {
+                    return Err(TransferError::InvalidChangeAddress {
+                        address: address.clone(),
+                    });
+                }
+                Some(script)
+            }
+            None => change_script.clone().filter(|script| script.is_p2pkh()),
+        };
+
         let mut wallet = self.get_wallet();
 
         let mut tx_builder = wallet.build_tx();
+        // Keep the recipients at the head of the outputs to find the change outputs after them.
+        // The outputs are shuffled after the change outputs are processed.
+        tx_builder.ordering(TxOrdering::Untouched);
+        if let Some(change_script) = &change_script {
+            tx_builder.drain_to(change_script.clone());
+        }
         params.iter().try_for_each(|param| {
             let address = Address::from_str(&param.to_address).map_err(|_| {
                 TransferError::FailedToParseAddress {
@@ -943,6 +2603,43 @@ impl HdWallet {
                 .map_err(|e| TransferError::FailedToCreateTransaction {
                     cause_description: e.to_string(),
                 })?;
+
+        // The outputs after the recipients are the change outputs.
+        // Send the token change to the colored change address. The change output of the wallet
+        // is P2PKH, so replacing it by the other P2PKH script does not change the fee.
+        let has_change = psbt.unsigned_tx.output.len() > params.len();
+        if has_change && !options.allow_change {
+            // The transaction is discarded, so free up the change addresses used by it.
+            wallet.cancel_tx(&psbt.unsigned_tx);
+            return Err(TransferError::ChangeNotAllowed);
+        }
+        for (output, psbt_output) in psbt
+            .unsigned_tx
+            .output
+            .iter_mut()
+            .zip(psbt.outputs.iter_mut())
+            .skip(params.len())
+        {
+            if let (Some(color_id), Some(colored_change_script)) =
+                (output.script_pubkey.color_id(), &colored_change_script)
+            {
+                output.script_pubkey = colored_change_script.add_color(color_id).map_err(|e| {
+                    TransferError::FailedToCreateTransaction {
+                        cause_description: e.to_string(),
+                    }
+                })?;
+                *psbt_output = Default::default();
+            }
+        }
+        let mut outputs = psbt
+            .unsigned_tx
+            .output
+            .drain(..)
+            .zip(psbt.outputs.drain(..))
+            .collect::<Vec<_>>();
+        outputs.shuffle(&mut secp256k1::rand::thread_rng());
+        (psbt.unsigned_tx.output, psbt.outputs) = outputs.into_iter().unzip();
+
         wallet
             .sign(&mut psbt, SignOptions::default())
             .map_err(|e| TransferError::FailedToCreateTransaction {
@@ -960,8 +2657,11 @@ impl HdWallet {
                 url,
                 user,
                 password,
+                headers,
+                token_provider,
             } => {
-                let client = Self::create_esplora_client(url, user, password);
+                let client =
+                    Self::create_esplora_client(url, user, password, headers, token_provider);
                 client
                     .broadcast(&tx)
                     .map_err(|e| TransferError::EsploraClient {
@@ -995,8 +2695,11 @@ impl HdWallet {
                 url,
                 user,
                 password,
+                headers,
+                token_provider,
             } => {
-                let client = Self::create_esplora_client(url, user, password);
+                let client =
+                    Self::create_esplora_client(url, user, password, headers, token_provider);
                 let tx = client.get_tx(&txid_parsed).map_err(|e| {
                     GetTransactionError::EsploraClientError {
                         cause_description: e.to_string(),
@@ -1046,8 +2749,11 @@ impl HdWallet {
                 url,
                 user,
                 password,
+                headers,
+                token_provider,
             } => {
-                let client = Self::create_esplora_client(url, user, password);
+                let client =
+                    Self::create_esplora_client(url, user, password, headers, token_provider);
                 tx.output
                     .iter()
                     .enumerate()
@@ -1128,11 +2834,17 @@ impl HdWallet {
         public_key: String,
         contract: String,
         color_id: Option<String>,
+        encoding: Option<ContractEncoding>,
     ) -> Result<String, CalcPayToContractAddressError> {
         let wallet = self.get_wallet();
         let payment_base = PublicKey::from_str(&public_key)
             .map_err(|_| CalcPayToContractAddressError::FailedToParsePublicKey)?;
-        let contract = contract.as_bytes().to_vec();
+        let contract = encoding
+            .unwrap_or(ContractEncoding::Utf8)
+            .decode(&contract)
+            .map_err(|e| CalcPayToContractAddressError::ContractError {
+                cause_description: e,
+            })?;
         let color_id = match color_id {
             Some(id) => Some(
                 ColorIdentifier::from_str(&id)
@@ -1148,29 +2860,340 @@ impl HdWallet {
         Ok(address.to_string())
     }
 
-    pub fn store_contract(&self, contract: Contract) -> Result<Contract, StoreContractError> {
-        let mut wallet = self.get_wallet();
-        let payment_base = PublicKey::from_str(&contract.payment_base)
-            .map_err(|_| StoreContractError::FailedToParsePublicKey)?;
-        let contract = wallet
-            .store_contract(
-                contract.contract_id,
-                contract.contract.as_bytes().to_vec(),
-                payment_base,
-                contract.payable,
-            )
-            .map_err(|e| StoreContractError::ContractError {
+    pub fn verify_p2c_address(
+        &self,
+        address: String,
+        public_key: String,
+        contract: String,
+        encoding: Option<ContractEncoding>,
+    ) -> Result<VerifyP2cAddressResult, VerifyP2cAddressError> {
+        let script = Address::from_str(&address)
+            .map_err(|_| VerifyP2cAddressError::FailedToParseAddress {
+                address: address.clone(),
+            })?
+            .require_network(self.network)
+            .map_err(|_| VerifyP2cAddressError::WrongNetworkAddress {
+                address: address.clone(),
+            })?
+            .script_pubkey();
+        let payment_base = PublicKey::from_str(&public_key)
+            .map_err(|_| VerifyP2cAddressError::FailedToParsePublicKey)?;
+        let contract = encoding
+            .unwrap_or(ContractEncoding::Utf8)
+            .decode(&contract)
+            .map_err(|e| VerifyP2cAddressError::ContractError {
+                cause_description: e,
+            })?;
+
+        // Calculate the address with the same color as the given address
+        let expected = self
+            .get_wallet()
+            .create_pay_to_contract_address(&payment_base, contract.clone(), script.color_id())
+            .map_err(|e| VerifyP2cAddressError::ContractError {
                 cause_description: e.to_string(),
             })?;
+        Ok(VerifyP2cAddressResult {
+            matches: expected.script_pubkey() == script,
+            expected_address: expected.to_string(),
+            commitment: p2c_commitment(&payment_base, &contract).to_string(),
+        })
+    }
+
+    pub fn store_contract(&self, contract: Contract) -> Result<Contract, StoreContractError> {
+        let mut wallet = self.get_wallet();
+        let payment_base = PublicKey::from_str(&contract.payment_base)
+            .map_err(|_| StoreContractError::FailedToParsePublicKey)?;
+        let encoding = contract.encoding.unwrap_or(ContractEncoding::Utf8);
+        let content =
+            encoding
+                .decode(&contract.contract)
+                .map_err(|e| StoreContractError::ContractError {
+                    cause_description: e,
+                })?;
+        let contract = self
+            .store_contract_bytes(
+                &mut wallet,
+                contract.contract_id,
+                content,
+                payment_base,
+                contract.payable,
+                encoding,
+            )
+            .map_err(|e| StoreContractError::ContractError {
+                cause_description: e,
+            })?;
         Ok(contract.into())
     }
 
+    /// Store the contract into the wallet and its information into the metadata.
+    /// The deleted contract is stored again if it has the same content and payment base.
+    /// The metadata is rolled back if the wallet fails to store the contract.
+    fn store_contract_bytes(
+        &self,
+        wallet: &mut Wallet,
+        contract_id: String,
+        content: Vec<u8>,
+        payment_base: PublicKey,
+        payable: bool,
+        encoding: ContractEncoding,
+    ) -> Result<StoredContract, String> {
+        // The contracts with the same payment base and content share the pay to contract
+        // address, so the funds to the address could not be attributed to one of them.
+        let script = wallet
+            .create_pay_to_contract_address(&payment_base, content.clone(), None)
+            .map_err(|e| e.to_string())?
+            .script_pubkey();
+        for contract in self.stored_contracts(wallet).map_err(|e| e.to_string())? {
+            if contract.contract_id != contract_id
+                && Self::contract_script(wallet, &contract)? == script
+            {
+                return Err(format!(
+                    "The contract {} has the same pay to contract address",
+                    contract.contract_id
+                ));
+            }
+        }
+
+        let previous = self
+            .metadata
+            .get_contract_info(&contract_id)
+            .map_err(|e| e.to_string())?;
+        let deleted = wallet_contracts(wallet)
+            .into_iter()
+            .find(|c| c.contract_id == contract_id)
+            .filter(|_| previous.as_ref().is_some_and(|info| info.deleted));
+        self.metadata
+            .put_contract_info(
+                &contract_id,
+                &ContractInfo {
+                    encoding,
+                    deleted: false,
+                },
+            )
+            .map_err(|e| e.to_string())?;
+
+        let result = match deleted {
+            Some(contract) => {
+                if contract.contract != content || contract.payment_base != payment_base {
+                    Err(format!(
+                        "The contract id {} is used by the deleted contract",
+                        contract_id
+                    ))
+                } else {
+                    wallet
+                        .update_contract(contract_id.clone(), payable)
+                        .map(|_| StoredContract {
+                            payable,
+                            ..StoredContract::new(contract, Some(encoding))
+                        })
+                        .map_err(|e| e.to_string())
+                }
+            }
+            None => wallet
+                .store_contract(contract_id.clone(), content, payment_base, payable)
+                .map(|contract| StoredContract::new(contract, Some(encoding)))
+                .map_err(|e| e.to_string()),
+        };
+        if result.is_err() {
+            let rollback = match &previous {
+                Some(info) => self.metadata.put_contract_info(&contract_id, info),
+                None => self.metadata.delete_contract_info(&contract_id),
+            };
+            if let Err(e) = rollback {
+                log::warn!("Failed to roll back the contract {}: {}", contract_id, e);
+            }
+        }
+        result
+    }
+
+    /// The contracts in the wallet store which are not deleted, ordered by the contract id.
+    fn stored_contracts(&self, wallet: &Wallet) -> rusqlite::Result<Vec<StoredContract>> {
+        let info = self.metadata.list_contract_info()?;
+        let mut contracts: Vec<StoredContract> = wallet_contracts(wallet)
+            .into_iter()
+            .filter_map(|contract| match info.get(&contract.contract_id) {
+                Some(info) if info.deleted => None,
+                info => Some(StoredContract::new(contract, info.map(|i| i.encoding))),
+            })
+            .collect();
+        contracts.sort_by(|a, b| a.contract_id.cmp(&b.contract_id));
+        Ok(contracts)
+    }
+
+    fn stored_contract(
+        &self,
+        wallet: &Wallet,
+        contract_id: &str,
+    ) -> rusqlite::Result<Option<StoredContract>> {
+        Ok(self
+            .stored_contracts(wallet)?
+            .into_iter()
+            .find(|contract| contract.contract_id == contract_id))
+    }
+
+    pub fn export_contracts(&self) -> Result<String, ExportContractsError> {
+        let contracts = self.stored_contracts(&self.get_wallet()).map_err(|e| {
+            ExportContractsError::WalletDBError {
+                cause_description: e.to_string(),
+            }
+        })?;
+        let contracts: Vec<serde_json::Value> = contracts
+            .into_iter()
+            .map(|c| {
+                serde_json::json!({
+                    "contract_id": c.contract_id,
+                    "contract": c.contract.to_lower_hex_string(),
+                    "payment_base": c.payment_base,
+                    "payable": c.payable,
+                    "encoding": c.encoding.as_str(),
+                })
+            })
+            .collect();
+        let mut bundle = serde_json::json!({
+            "version": CONTRACT_BUNDLE_VERSION,
+            "network": self.network.to_string(),
+            "contracts": contracts,
+        });
+
+        let hmac = contract_bundle_hmac(&self.get_wallet(), &bundle).map_err(|e| {
+            ExportContractsError::KeyError {
+                cause_description: e,
+            }
+        })?;
+        bundle["hmac"] = serde_json::Value::String(hmac.to_string());
+        Ok(bundle.to_string())
+    }
+
+    pub fn import_contracts(&self, bundle: String) -> Result<Vec<Contract>, ImportContractsError> {
+        let invalid = |cause: &str| ImportContractsError::InvalidBundle {
+            cause_description: cause.to_string(),
+        };
+        let mut bundle: serde_json::Value =
+            serde_json::from_str(&bundle).map_err(|e| invalid(&e.to_string()))?;
+        let hmac = match bundle.as_object_mut().and_then(|b| b.remove("hmac")) {
+            Some(serde_json::Value::String(hmac)) => hmac,
+            _ => return Err(invalid("no hmac")),
+        };
+        let version = bundle["version"]
+            .as_u64()
+            .ok_or_else(|| invalid("no version"))?;
+        if version != CONTRACT_BUNDLE_VERSION {
+            return Err(ImportContractsError::UnsupportedVersion { version });
+        }
+        if bundle["network"].as_str() != Some(self.network.to_string().as_str()) {
+            return Err(ImportContractsError::WrongNetwork);
+        }
+
+        let mut wallet = self.get_wallet();
+        let expected =
+            contract_bundle_hmac(&wallet, &bundle).map_err(|e| ImportContractsError::KeyError {
+                cause_description: e,
+            })?;
+        let hmac = Vec::from_hex(&hmac).map_err(|_| ImportContractsError::InvalidHmac)?;
+        if !constant_time_eq(expected.as_byte_array(), &hmac) {
+            return Err(ImportContractsError::InvalidHmac);
+        }
+
+        let mut entries: Vec<(String, Vec<u8>, PublicKey, bool, ContractEncoding)> = Vec::new();
+        for entry in bundle["contracts"]
+            .as_array()
+            .ok_or_else(|| invalid("no contracts"))?
+        {
+            let field = |name: &str| {
+                entry[name]
+                    .as_str()
+                    .ok_or_else(|| invalid(&format!("no {}", name)))
+            };
+            let contract_id = field("contract_id")?.to_string();
+            let content = Vec::from_hex(field("contract")?).map_err(|e| invalid(&e.to_string()))?;
+            let payment_base =
+                PublicKey::from_str(field("payment_base")?).map_err(|e| invalid(&e.to_string()))?;
+            let encoding =
+                ContractEncoding::from_str(field("encoding")?).map_err(|e| invalid(&e))?;
+            let payable = entry["payable"]
+                .as_bool()
+                .ok_or_else(|| invalid("no payable"))?;
+            if entries.iter().any(|(id, ..)| *id == contract_id) {
+                return Err(invalid(&format!("duplicated contract id {}", contract_id)));
+            }
+            entries.push((contract_id, content, payment_base, payable, encoding));
+        }
+
+        // Validate all the contracts before storing them not to import the bundle partially.
+        let contract_err = |e: String| ImportContractsError::ContractError {
+            cause_description: e,
+        };
+        let stored =
+            self.stored_contracts(&wallet)
+                .map_err(|e| ImportContractsError::WalletDBError {
+                    cause_description: e.to_string(),
+                })?;
+        let mut scripts = Vec::new();
+        for contract in &stored {
+            let script = Self::contract_script(&wallet, contract).map_err(contract_err)?;
+            scripts.push((contract.contract_id.clone(), script));
+        }
+        let existing = wallet_contracts(&wallet);
+        let mut new_entries = Vec::new();
+        for (contract_id, content, payment_base, payable, encoding) in entries {
+            match existing.iter().find(|c| c.contract_id == contract_id) {
+                Some(c) if c.contract != content || c.payment_base != payment_base => {
+                    return Err(contract_err(format!(
+                        "The contract id {} is used by the other contract",
+                        contract_id
+                    )));
+                }
+                // The contract already stored is skipped
+                Some(_) if stored.iter().any(|c| c.contract_id == contract_id) => continue,
+                _ => {}
+            }
+            let script = wallet
+                .create_pay_to_contract_address(&payment_base, content.clone(), None)
+                .map_err(|e| contract_err(e.to_string()))?
+                .script_pubkey();
+            if let Some((other, _)) = scripts.iter().find(|(_, s)| *s == script) {
+                return Err(contract_err(format!(
+                    "The contract {} has the same pay to contract address",
+                    other
+                )));
+            }
+            scripts.push((contract_id.clone(), script));
+            new_entries.push((contract_id, content, payment_base, payable, encoding));
+        }
+
+        let mut imported = Vec::new();
+        for (contract_id, content, payment_base, payable, encoding) in new_entries {
+            let contract = self
+                .store_contract_bytes(
+                    &mut wallet,
+                    contract_id,
+                    content,
+                    payment_base,
+                    payable,
+                    encoding,
+                )
+                .map_err(contract_err)?;
+            imported.push(contract.into());
+        }
+        Ok(imported)
+    }
+
     pub fn update_contract(
         &self,
         contract_id: String,
         payable: bool,
     ) -> Result<(), UpdateContractError> {
         let mut wallet = self.get_wallet();
+        let info = self.metadata.get_contract_info(&contract_id).map_err(|e| {
+            UpdateContractError::ContractError {
+                cause_description: e.to_string(),
+            }
+        })?;
+        if info.is_some_and(|info| info.deleted) {
+            return Err(UpdateContractError::ContractError {
+                cause_description: format!("The contract {} is deleted", contract_id),
+            });
+        }
         wallet.update_contract(contract_id, payable).map_err(|e| {
             UpdateContractError::ContractError {
                 cause_description: e.to_string(),
@@ -1179,176 +3202,702 @@ impl HdWallet {
         Ok(())
     }
 
+    pub fn list_contracts(&self) -> Result<Vec<Contract>, ListContractsError> {
+        let contracts = self.stored_contracts(&self.get_wallet()).map_err(|e| {
+            ListContractsError::WalletDBError {
+                cause_description: e.to_string(),
+            }
+        })?;
+        Ok(contracts.into_iter().map(Contract::from).collect())
+    }
+
+    pub fn get_contract(&self, contract_id: String) -> Result<Contract, GetContractError> {
+        self.stored_contract(&self.get_wallet(), &contract_id)
+            .map_err(|e| GetContractError::WalletDBError {
+                cause_description: e.to_string(),
+            })?
+            .map(Contract::from)
+            .ok_or(GetContractError::UnknownContract)
+    }
+
+    pub fn delete_contract(&self, contract_id: String) -> Result<(), DeleteContractError> {
+        let map_db_err = |e: rusqlite::Error| DeleteContractError::WalletDBError {
+            cause_description: e.to_string(),
+        };
+        let mut wallet = self.get_wallet();
+        let contract = match self
+            .stored_contract(&wallet, &contract_id)
+            .map_err(map_db_err)?
+        {
+            Some(contract) => contract,
+            // Deleting the deleted contract again succeeds
+            None if wallet_contracts(&wallet)
+                .iter()
+                .any(|c| c.contract_id == contract_id) =>
+            {
+                return Ok(())
+            }
+            None => return Err(DeleteContractError::UnknownContract),
+        };
+
+        let script = Self::contract_script(&wallet, &contract).map_err(|e| {
+            DeleteContractError::ContractError {
+                cause_description: e,
+            }
+        })?;
+        if wallet
+            .list_unspent()
+            .any(|utxo| utxo.txout.script_pubkey.remove_color() == script)
+        {
+            return Err(DeleteContractError::ContractHasUnspentFunds);
+        }
+
+        // The wallet store can not remove the contract, so make it unpayable and hide it instead.
+        // The contract is hidden after it becomes unpayable not to leave the hidden payable one.
+        wallet
+            .update_contract(contract_id.clone(), false)
+            .map_err(|e| DeleteContractError::ContractError {
+                cause_description: e.to_string(),
+            })?;
+        self.metadata
+            .put_contract_info(
+                &contract_id,
+                &ContractInfo {
+                    encoding: contract.encoding,
+                    deleted: true,
+                },
+            )
+            .map_err(map_db_err)?;
+        Ok(())
+    }
+
+    pub fn contract_balance(
+        &self,
+        contract_id: String,
+    ) -> Result<Vec<ColoredAmount>, ContractFundsError> {
+        let mut balances: BTreeMap<Option<String>, u64> = BTreeMap::new();
+        for utxo in self.list_contract_utxos(contract_id)? {
+            *balances.entry(utxo.color_id).or_default() += utxo.amount;
+        }
+        Ok(balances
+            .into_iter()
+            .map(|(color_id, amount)| ColoredAmount { color_id, amount })
+            .collect())
+    }
+
+    pub fn list_contract_utxos(
+        &self,
+        contract_id: String,
+    ) -> Result<Vec<TxOut>, ContractFundsError> {
+        let wallet = self.get_wallet();
+        let contracts =
+            self.stored_contracts(&wallet)
+                .map_err(|e| ContractFundsError::WalletDBError {
+                    cause_description: e.to_string(),
+                })?;
+        let contract = contracts
+            .iter()
+            .find(|c| c.contract_id == contract_id)
+            .ok_or(ContractFundsError::UnknownContract)?;
+        let script = Self::contract_script(&wallet, contract).map_err(|e| {
+            ContractFundsError::ContractError {
+                cause_description: e,
+            }
+        })?;
+        // The funds to the address shared by the contracts stored before the duplication was
+        // rejected are attributed only to the first contract not to be counted twice.
+        let owner = contracts
+            .iter()
+            .find(|c| Self::contract_script(&wallet, c).is_ok_and(|s| s == script));
+        if owner.is_some_and(|owner| owner.contract_id != contract_id) {
+            return Ok(Vec::new());
+        }
+        let utxos = wallet
+            .list_unspent()
+            .filter(|utxo| utxo.txout.script_pubkey.remove_color() == script)
+            .map(|utxo| TxOut {
+                txid: utxo.outpoint.txid.to_string(),
+                index: utxo.outpoint.vout,
+                amount: utxo.txout.value.to_tap(),
+                color_id: utxo.txout.script_pubkey.color_id().map(|id| id.to_string()),
+                address: Address::from_script(&utxo.txout.script_pubkey, self.network)
+                    .unwrap()
+                    .to_string(),
+                unspent: true,
+            })
+            .collect();
+        Ok(utxos)
+    }
+
+    /// The uncolored script pubkey of the pay to contract address of the contract.
+    fn contract_script(wallet: &Wallet, contract: &StoredContract) -> Result<ScriptBuf, String> {
+        let payment_base =
+            PublicKey::from_str(&contract.payment_base).map_err(|e| e.to_string())?;
+        let address = wallet
+            .create_pay_to_contract_address(&payment_base, contract.contract.clone(), None)
+            .map_err(|e| e.to_string())?;
+        Ok(address.script_pubkey())
+    }
+
     pub fn check_trust_layer_refund(
         &self,
         txid: String,
         color_id: String,
     ) -> Result<u64, CheckTrustLayerRefundError> {
-        let wallet = self.get_wallet();
+        let target = self.refund_target(Some(&color_id), None)?;
+        let (_, legacy_total) = self.check_refund(txid, &target)?;
+        Ok(legacy_total)
+    }
+
+    pub fn check_trust_layer_refund_report(
+        &self,
+        txid: String,
+        color_id: String,
+    ) -> Result<TrustLayerRefundReport, CheckTrustLayerRefundError> {
+        let target = self.refund_target(Some(&color_id), None)?;
+        let (report, _) = self.check_refund(txid, &target)?;
+        Ok(report)
+    }
+
+    pub fn check_trust_layer_tpc_refund_report(
+        &self,
+        txid: String,
+        refund_address: Option<String>,
+    ) -> Result<TrustLayerRefundReport, CheckTrustLayerRefundError> {
+        let target = self.refund_target(None, refund_address.as_deref())?;
+        let (report, _) = self.check_refund(txid, &target)?;
+        Ok(report)
+    }
+
+    fn check_refund(
+        &self,
+        txid: String,
+        target: &RefundTarget,
+    ) -> Result<(TrustLayerRefundReport, u64), CheckTrustLayerRefundError> {
         let txid_parsed = txid
             .parse::<MalFixTxid>()
             .map_err(|_| CheckTrustLayerRefundError::FailedToParseTxid { txid: txid.clone() })?;
-        let color_id = ColorIdentifier::from_str(&color_id)
-            .map_err(|_| CheckTrustLayerRefundError::InvalidColorId)?;
 
-        match &self.backend {
-            BackendClient::Esplora {
-                url,
-                user,
-                password,
-            } => {
-                let client = Self::create_esplora_client(url, user, password);
+        let client = self.refund_client()?;
+        let tx = client
+            .get_tx(&txid_parsed)?
+            .ok_or(CheckTrustLayerRefundError::UnknownTxid)?;
+        self.refund_report(&client, &txid_parsed, &tx, target)
+    }
 
-                // get transactions that uses the txid as input
-                let opt_tx = client.get_tx(&txid_parsed).map_err(|e| {
-                    CheckTrustLayerRefundError::EsploraClientError {
-                        cause_description: e.to_string(),
+    pub fn check_trust_layer_refunds(
+        &self,
+        requests: Vec<TrustLayerRefundRequest>,
+    ) -> Result<Vec<TrustLayerRefundResult>, CheckTrustLayerRefundError> {
+        if requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        let client = self.refund_client()?;
+
+        // The transaction is fetched once for the requests of the same txid
+        let mut txs: HashMap<
+            String,
+            Result<(MalFixTxid, Transaction), CheckTrustLayerRefundError>,
+        > = HashMap::new();
+        let mut results = Vec::new();
+        for request in requests {
+            let target = match self.refund_target(
+                request.color_id.as_deref(),
+                request.refund_address.as_deref(),
+            ) {
+                Ok(target) => target,
+                Err(e) => {
+                    results.push(TrustLayerRefundResult {
+                        txid: request.txid,
+                        color_id: request.color_id,
+                        report: None,
+                        error: Some(e.to_string()),
+                    });
+                    continue;
+                }
+            };
+            let tx = txs.entry(request.txid.clone()).or_insert_with(|| {
+                let txid_parsed = request.txid.parse::<MalFixTxid>().map_err(|_| {
+                    CheckTrustLayerRefundError::FailedToParseTxid {
+                        txid: request.txid.clone(),
                     }
                 })?;
-                let tx = match opt_tx {
-                    Some(tx) => tx,
-                    None => return Err(CheckTrustLayerRefundError::UnknownTxid),
-                };
+                let tx = client
+                    .get_tx(&txid_parsed)?
+                    .ok_or(CheckTrustLayerRefundError::UnknownTxid)?;
+                Ok((txid_parsed, tx))
+            });
+            let result = match tx {
+                Ok((txid_parsed, tx)) => self
+                    .refund_report(&client, txid_parsed, tx, &target)
+                    .map(|(report, _)| report),
+                Err(e) => Err(e.clone()),
+            };
+            let (report, error) = match result {
+                Ok(report) => (Some(report), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            results.push(TrustLayerRefundResult {
+                txid: request.txid,
+                color_id: request.color_id,
+                report,
+                error,
+            });
+        }
+        Ok(results)
+    }
+
+    pub fn refund_trust_layer_transfer(
+        &self,
+        txid: String,
+        refund_address: String,
+        contract_id: Option<String>,
+        p2c_address: Option<String>,
+    ) -> Result<String, RefundTrustLayerTransferError> {
+        let map_db_err = |e: rusqlite::Error| RefundTrustLayerTransferError::WalletDBError {
+            cause_description: e.to_string(),
+        };
+        let map_contract_err = |e: String| RefundTrustLayerTransferError::ContractError {
+            cause_description: e,
+        };
+        let parse_address = |address: &String| -> Result<ScriptBuf, RefundTrustLayerTransferError> {
+            Address::from_str(address)
+                .map_err(|_| RefundTrustLayerTransferError::FailedToParseAddress {
+                    address: address.clone(),
+                })?
+                .require_network(self.network)
+                .map_err(|_| RefundTrustLayerTransferError::WrongNetworkAddress {
+                    address: address.clone(),
+                })
+                .map(|address| address.script_pubkey())
+        };
+
+        let txid_parsed = txid
+            .parse::<MalFixTxid>()
+            .map_err(|_| RefundTrustLayerTransferError::FailedToParseTxid { txid: txid.clone() })?;
+        // The refund address is colored with the color of each refunded output.
+        let refund_address_script = parse_address(&refund_address)?;
+        let refund_script = refund_address_script.remove_color();
+        let p2c_script = p2c_address
+            .as_ref()
+            .map(parse_address)
+            .transpose()?
+            .map(|script| script.remove_color());
+
+        let (utxos, refunded) = {
+            let wallet = self.get_wallet();
+            let contract = match (&contract_id, &p2c_script) {
+                (Some(contract_id), _) => self
+                    .stored_contract(&wallet, contract_id)
+                    .map_err(map_db_err)?
+                    .ok_or(RefundTrustLayerTransferError::UnknownContract)?,
+                (None, Some(p2c_script)) => {
+                    let mut found = None;
+                    for contract in self.stored_contracts(&wallet).map_err(map_db_err)? {
+                        if Self::contract_script(&wallet, &contract).map_err(map_contract_err)?
+                            == *p2c_script
+                        {
+                            found = Some(contract);
+                            break;
+                        }
+                    }
+                    found.ok_or(RefundTrustLayerTransferError::UnknownContract)?
+                }
+                (None, None) => return Err(RefundTrustLayerTransferError::ContractNotSpecified),
+            };
+            let script = Self::contract_script(&wallet, &contract).map_err(map_contract_err)?;
+            if p2c_script.is_some_and(|p2c_script| p2c_script != script) {
+                return Err(RefundTrustLayerTransferError::ContractMismatch);
+            }
+            if !contract.payable {
+                return Err(RefundTrustLayerTransferError::ContractNotPayable);
+            }
 
-                // filter outputs that send the color_id token to other wallet
-                let transfer_txouts = tx.output.iter().enumerate().filter(|(_, txout)| {
-                    // filter outputs that send the color_id token to other wallet
-                    let output_color_id = txout.script_pubkey.color_id();
-                    let script_pubkey = txout.script_pubkey.remove_color();
-                    output_color_id.is_some()
-                        && output_color_id.unwrap() == color_id
-                        && !wallet.is_mine(script_pubkey.as_script()) // exclude change outputs
+            wallet
+                .list_unspent()
+                .filter(|utxo| {
+                    utxo.outpoint.txid == txid_parsed
+                        && utxo.txout.script_pubkey.remove_color() == script
+                })
+                .map(|utxo| {
+                    let txout = TxOut {
+                        txid: utxo.outpoint.txid.to_string(),
+                        index: utxo.outpoint.vout,
+                        amount: utxo.txout.value.to_tap(),
+                        color_id: utxo.txout.script_pubkey.color_id().map(|id| id.to_string()),
+                        address: Address::from_script(&utxo.txout.script_pubkey, self.network)
+                            .unwrap()
+                            .to_string(),
+                        unspent: true,
+                    };
+                    let color_id = utxo.txout.script_pubkey.color_id();
+                    (txout, (color_id, utxo.txout.value.to_tap()))
+                })
+                .unzip::<_, _, Vec<_>, Vec<_>>()
+        };
+        if utxos.is_empty() {
+            return Err(RefundTrustLayerTransferError::NoRefundableOutput);
+        }
+        // The colored refund address is accepted only if all the refunded outputs have its color.
+        if let Some(color_id) = refund_address_script.color_id() {
+            if refunded.iter().any(|(c, _)| *c != Some(color_id)) {
+                return Err(RefundTrustLayerTransferError::InvalidRefundAddress {
+                    address: refund_address,
                 });
+            }
+        }
 
-                // fold the amount of refund txout value that is sent back to the wallet
-                transfer_txouts.into_iter().try_fold(
-                    0u64,
-                    |acc, (index, _)| -> Result<u64, CheckTrustLayerRefundError> {
-                        let output_status = client
-                            .get_output_status(&txid_parsed, index as u64)
-                            .map_err(|e| CheckTrustLayerRefundError::EsploraClientError {
-                                cause_description: e.to_string(),
-                            })?;
-                        match output_status {
-                            Some(OutputStatus {
-                                txid: Some(spending_txid),
-                                ..
-                            }) => {
-                                let opt_tx = client.get_tx(&spending_txid).map_err(|e| {
-                                    CheckTrustLayerRefundError::EsploraClientError {
-                                        cause_description: e.to_string(),
-                                    }
-                                })?;
-                                let tx = match opt_tx {
-                                    Some(tx) => tx,
-                                    None => return Err(
-                                        CheckTrustLayerRefundError::CannotFoundRefundTransaction {
-                                            txid: spending_txid.to_string(),
-                                        },
-                                    ),
-                                };
-                                let refund_txout = tx.output.iter().find(|txout| {
-                                    if txout.script_pubkey.color_id().is_some()
-                                        && txout.script_pubkey.color_id().unwrap() == color_id
-                                    {
-                                        let script_pubkey = txout.script_pubkey.remove_color();
-                                        wallet.is_mine(script_pubkey.as_script())
-                                    } else {
-                                        false
-                                    }
-                                });
-                                match refund_txout {
-                                    Some(refund_txout) => Ok(acc + refund_txout.value.to_tap()),
-                                    None => Ok(acc),
-                                }
-                            }
-                            Some(_) => Ok(acc),
-                            None => Ok(acc),
+        // Refund the total amount of each color by one output.
+        let mut amounts: Vec<(Option<ColorIdentifier>, u64)> = Vec::new();
+        for (color_id, amount) in refunded {
+            match amounts.iter_mut().find(|(c, _)| *c == color_id) {
+                Some((_, total)) => *total += amount,
+                None => amounts.push((color_id, amount)),
+            }
+        }
+        let params = amounts
+            .into_iter()
+            .map(|(color_id, amount)| {
+                let script = match color_id {
+                    Some(color_id) => refund_script.add_color(color_id).map_err(|e| {
+                        RefundTrustLayerTransferError::FailedToCreateTransaction {
+                            cause_description: e.to_string(),
                         }
-                    },
-                )
+                    })?,
+                    None => refund_script.clone(),
+                };
+                let to_address = Address::from_script(&script, self.network)
+                    .map_err(
+                        |e| RefundTrustLayerTransferError::FailedToCreateTransaction {
+                            cause_description: e.to_string(),
+                        },
+                    )?
+                    .to_string();
+                Ok(TransferParams { amount, to_address })
+            })
+            .collect::<Result<Vec<_>, RefundTrustLayerTransferError>>()?;
+
+        self.transfer(params, utxos).map_err(|e| match e {
+            TransferError::InsufficientFund => RefundTrustLayerTransferError::InsufficientFund,
+            TransferError::EsploraClient { cause_description } => {
+                RefundTrustLayerTransferError::EsploraClient { cause_description }
+            }
+            TransferError::ElectrumClient { cause_description } => {
+                RefundTrustLayerTransferError::ElectrumClient { cause_description }
+            }
+            e => RefundTrustLayerTransferError::FailedToCreateTransaction {
+                cause_description: e.to_string(),
+            },
+        })
+    }
+
+    /// The color id None means the transfer of TPC. The refund address narrows the refund outputs
+    /// down to the outputs paid to it.
+    fn refund_target(
+        &self,
+        color_id: Option<&str>,
+        refund_address: Option<&str>,
+    ) -> Result<RefundTarget, CheckTrustLayerRefundError> {
+        let color_id = color_id
+            .map(|id| {
+                ColorIdentifier::from_str(id)
+                    .map_err(|_| CheckTrustLayerRefundError::InvalidColorId)
+            })
+            .transpose()?;
+        let refund_script = match refund_address {
+            Some(address) => {
+                let script = Address::from_str(address)
+                    .map_err(|_| CheckTrustLayerRefundError::FailedToParseAddress {
+                        address: address.to_string(),
+                    })?
+                    .require_network(self.network)
+                    .map_err(|_| CheckTrustLayerRefundError::WrongNetworkAddress {
+                        address: address.to_string(),
+                    })?
+                    .script_pubkey();
+                if script.color_id().is_some_and(|id| Some(id) != color_id) {
+                    return Err(CheckTrustLayerRefundError::InvalidRefundAddress {
+                        address: address.to_string(),
+                    });
+                }
+                Some(script.remove_color())
             }
+            None => None,
+        };
+        Ok(RefundTarget {
+            color_id,
+            refund_script,
+        })
+    }
+
+    fn refund_client(&self) -> Result<RefundClient, CheckTrustLayerRefundError> {
+        match &self.backend {
+            BackendClient::Esplora {
+                url,
+                user,
+                password,
+                headers,
+                token_provider,
+            } => Ok(RefundClient::Esplora(Self::create_esplora_client(
+                url,
+                user,
+                password,
+                headers,
+                token_provider,
+            ))),
             BackendClient::Electrum { url } => {
                 let client = Self::create_electrum_client(url).map_err(|e| {
                     CheckTrustLayerRefundError::ElectrumClientError {
                         cause_description: e.to_string(),
                     }
                 })?;
+                Ok(RefundClient::Electrum(client))
+            }
+        }
+    }
 
-                // get the transaction
-                let tx = client.inner.transaction_get(&txid_parsed).map_err(|e| {
-                    CheckTrustLayerRefundError::ElectrumClientError {
-                        cause_description: e.to_string(),
-                    }
-                })?;
-
-                // filter outputs that send the color_id token to other wallet
-                let transfer_txouts: Vec<_> = tx
-                    .output
-                    .iter()
-                    .enumerate()
-                    .filter(|(_, txout)| {
-                        let output_color_id = txout.script_pubkey.color_id();
-                        let script_pubkey = txout.script_pubkey.remove_color();
-                        output_color_id.is_some()
-                            && output_color_id.unwrap() == color_id
-                            && !wallet.is_mine(script_pubkey.as_script())
-                    })
-                    .collect();
-
-                let mut total_refund = 0u64;
-
-                for (index, txout) in transfer_txouts {
-                    // Get the transaction history for this output's script
-                    let history = client
-                        .inner
-                        .script_get_history(&txout.script_pubkey)
-                        .map_err(|e| CheckTrustLayerRefundError::ElectrumClientError {
-                            cause_description: e.to_string(),
-                        })?;
-
-                    // Find transactions that spend from our output (transactions after our transfer)
-                    for hist_item in history {
-                        if hist_item.tx_hash == txid_parsed {
-                            continue; // Skip our original transaction
-                        }
-
-                        // Get the spending transaction
-                        let spending_tx = client
-                            .inner
-                            .transaction_get(&hist_item.tx_hash)
-                            .map_err(|e| CheckTrustLayerRefundError::ElectrumClientError {
-                                cause_description: e.to_string(),
-                            })?;
-
-                        // Check if this transaction spends our output
-                        let spends_our_output = spending_tx.input.iter().any(|input| {
-                            input.previous_output.txid == txid_parsed
-                                && input.previous_output.vout == index as u32
-                        });
+    /// Check each output which transfers the token to the other wallet by the transaction and
+    /// whether it is refunded to this wallet by the transaction spending the output.
+    /// The refund outputs have the color of the target and pay to the refund script of the
+    /// target, or to the wallet if the target does not have the refund script.
+    /// Return the report and the total which check_trust_layer_refund has returned, where the
+    /// first refund output of the transaction spending each output is counted for each output.
+    /// The wallet is locked only while checking the scripts, not during the network requests.
+    fn refund_report(
+        &self,
+        client: &RefundClient,
+        txid: &MalFixTxid,
+        tx: &Transaction,
+        target: &RefundTarget,
+    ) -> Result<(TrustLayerRefundReport, u64), CheckTrustLayerRefundError> {
+        let is_color = |txout: &tapyrus::TxOut| txout.script_pubkey.color_id() == target.color_id;
+        let is_mine = |txout: &tapyrus::TxOut| {
+            let script_pubkey = txout.script_pubkey.remove_color();
+            self.get_wallet().is_mine(script_pubkey.as_script())
+        };
+        let is_refund = |txout: &tapyrus::TxOut| {
+            is_color(txout)
+                && match &target.refund_script {
+                    Some(script) => txout.script_pubkey.remove_color() == *script,
+                    None => is_mine(txout),
+                }
+        };
 
-                        if spends_our_output {
-                            // Look for refund output (output to our wallet with same color_id)
-                            let refund_txout = spending_tx.output.iter().find(|txout| {
-                                if txout.script_pubkey.color_id().is_some()
-                                    && txout.script_pubkey.color_id().unwrap() == color_id
-                                {
-                                    let script_pubkey = txout.script_pubkey.remove_color();
-                                    wallet.is_mine(script_pubkey.as_script())
-                                } else {
-                                    false
+        let mut report = TrustLayerRefundReport {
+            txid: txid.to_string(),
+            color_id: target.color_id.as_ref().map(|c| c.to_string()),
+            outputs: Vec::new(),
+            total_refund: 0,
+            unresolved_outputs: Vec::new(),
+        };
+        let mut legacy_total = 0u64;
+        // The transactions spending the outputs with their refund amounts and the positions of
+        // the outputs in the report. The transaction can spend several outputs.
+        let mut spending_txs: Vec<(MalFixTxid, Vec<u64>, Vec<usize>)> = Vec::new();
+
+        // exclude change outputs
+        for (index, txout) in tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, txout)| is_color(txout) && !is_mine(txout))
+        {
+            let index = index as u32;
+            let mut output = TransferredOutput {
+                index,
+                amount: txout.value.to_tap(),
+                address: Address::from_script(&txout.script_pubkey, self.network)
+                    .map(|a| a.to_string())
+                    .unwrap_or_default(),
+                status: RefundStatus::Unspent,
+                spending_txid: None,
+                refund_txid: None,
+                refund_amount: 0,
+            };
+
+            match client.spending_txid(txid, index, &txout.script_pubkey)? {
+                Some(spending_txid) => {
+                    let position = match spending_txs.iter().position(|(t, ..)| *t == spending_txid)
+                    {
+                        Some(position) => position,
+                        None => {
+                            let spending_tx = client.get_tx(&spending_txid)?.ok_or_else(|| {
+                                CheckTrustLayerRefundError::CannotFoundRefundTransaction {
+                                    txid: spending_txid.to_string(),
                                 }
-                            });
-                            if let Some(refund_txout) = refund_txout {
-                                total_refund += refund_txout.value.to_tap();
-                            }
+                            })?;
+                            let refunds = spending_tx
+                                .output
+                                .iter()
+                                .filter(|txout| is_refund(txout))
+                                .map(|txout| txout.value.to_tap())
+                                .collect();
+                            spending_txs.push((spending_txid, refunds, Vec::new()));
+                            spending_txs.len() - 1
                         }
+                    };
+                    let (_, refunds, positions) = &mut spending_txs[position];
+                    positions.push(report.outputs.len());
+                    legacy_total += refunds.first().copied().unwrap_or_default();
+
+                    output.spending_txid = Some(spending_txid.to_string());
+                    output.status = RefundStatus::Spent;
+                    if !refunds.is_empty() {
+                        output.status = RefundStatus::Refunded;
+                        output.refund_txid = Some(spending_txid.to_string());
                     }
                 }
+                None => report.unresolved_outputs.push(index),
+            }
+            report.outputs.push(output);
+        }
 
-                Ok(total_refund)
+        // All the refund outputs of the transaction are counted once.
+        for (_, refunds, positions) in spending_txs {
+            let total: u64 = refunds.iter().sum();
+            report.total_refund += total;
+            let amounts: Vec<u64> = positions
+                .iter()
+                .map(|p| report.outputs[*p].amount)
+                .collect();
+            for (position, refund) in positions.iter().zip(attribute_refund(total, &amounts)) {
+                report.outputs[*position].refund_amount = refund;
             }
         }
+        Ok((report, legacy_total))
+    }
+
+    pub fn start_notification_listener(
+        self: Arc<Self>,
+        listener: Box<dyn NotificationListener>,
+    ) -> Result<(), StartNotificationListenerError> {
+        let url = match &self.backend {
+            BackendClient::Electrum { url } => url.clone(),
+            BackendClient::Esplora { .. } => {
+                return Err(StartNotificationListenerError::UnsupportedBackend)
+            }
+        };
+
+        let mut worker = self
+            .notification_worker
+            .lock()
+            .expect("Failed to lock notification worker");
+        if worker.as_ref().is_some_and(|w| !w.is_finished()) {
+            return Err(StartNotificationListenerError::AlreadyStarted);
+        }
+
+        // Subscribe in the caller thread so that connection errors are reported to the caller.
+        // The first poll only records the current state and does not notify anything.
+        let mut notifier = ElectrumNotifier::new(url);
+        notifier.poll(&self, listener.as_ref()).map_err(|e| {
+            StartNotificationListenerError::ElectrumClientError {
+                cause_description: e.to_string(),
+            }
+        })?;
+
+        // The worker holds a weak reference so that the wallet can be dropped while listening.
+        let wallet = Arc::downgrade(&self);
+        *worker = Some(Worker::spawn(move || {
+            let wallet = wallet.upgrade()?;
+            if notifier.poll(&wallet, listener.as_ref()).is_err() {
+                // Reconnect and subscribe again in the next poll
+                notifier.disconnect();
+            }
+            Some(NOTIFICATION_POLL_INTERVAL)
+        }));
+        Ok(())
+    }
+
+    pub fn stop_notification_listener(&self) {
+        let worker = self
+            .notification_worker
+            .lock()
+            .expect("Failed to lock notification worker")
+            .take();
+        if let Some(worker) = worker {
+            worker.stop();
+        }
+    }
+
+    pub fn start_background_sync(
+        self: Arc<Self>,
+        interval_secs: u64,
+        max_backoff_secs: u64,
+        listener: Box<dyn SyncListener>,
+    ) -> Result<(), StartBackgroundSyncError> {
+        if interval_secs == 0 {
+            return Err(StartBackgroundSyncError::InvalidInterval);
+        }
+
+        let mut worker = self.sync_worker.lock().expect("Failed to lock sync worker");
+        if worker.as_ref().is_some_and(|w| !w.is_finished()) {
+            return Err(StartBackgroundSyncError::AlreadyStarted);
+        }
+
+        let interval = Duration::from_secs(interval_secs);
+        let max_backoff = Duration::from_secs(max_backoff_secs).max(interval);
+        let mut failures: u32 = 0;
+
+        // The worker holds a weak reference so that the wallet can be dropped while syncing.
+        let wallet = Arc::downgrade(&self);
+        *worker = Some(Worker::spawn(move || {
+            let wallet = wallet.upgrade()?;
+            match wallet.sync() {
+                Ok(result) => {
+                    failures = 0;
+                    listener.on_sync_completed(result);
+                    Some(interval)
+                }
+                Err(e) => {
+                    // Double the interval for each consecutive failure up to max_backoff
+                    failures = failures.saturating_add(1);
+                    listener.on_sync_failed(e.to_string());
+                    let backoff = interval
+                        .checked_mul(2u32.saturating_pow(failures))
+                        .unwrap_or(max_backoff);
+                    Some(backoff.min(max_backoff))
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    pub fn stop_background_sync(&self) {
+        let worker = self
+            .sync_worker
+            .lock()
+            .expect("Failed to lock sync worker")
+            .take();
+        if let Some(worker) = worker {
+            worker.stop();
+        }
+    }
+
+    /// Scripts to subscribe for the notifications.
+    /// Colored variants are included for the colors the wallet has ever received.
+    fn watched_scripts(&self) -> Vec<ScriptBuf> {
+        let wallet = self.get_wallet();
+        let mut color_ids: Vec<ColorIdentifier> = Vec::new();
+        for output in wallet.list_output() {
+            if let Some(color_id) = output.txout.script_pubkey.color_id() {
+                if !color_ids.contains(&color_id) {
+                    color_ids.push(color_id);
+                }
+            }
+        }
+
+        let mut scripts = Vec::new();
+        for keychain in [KeychainKind::External, KeychainKind::Internal] {
+            for (_, script) in wallet.spk_index().revealed_keychain_spks(&keychain) {
+                for color_id in &color_ids {
+                    if let Ok(colored) = script.add_color(color_id.clone()) {
+                        scripts.push(colored);
+                    }
+                }
+                scripts.push(script.to_owned());
+            }
+        }
+        scripts
     }
 
     pub fn sign_message(
@@ -1362,6 +3911,42 @@ impl HdWallet {
         let message_bytes = message.as_bytes();
         let message_hash: sha256::Hash = Hash::hash(message_bytes);
         let message = Message::from(message_hash);
+        let private_key = Self::private_key_of(&wallet, &public_key)?;
+        let sig = wallet.secp_ctx().sign_ecdsa(&message, &private_key);
+        Ok(sig.serialize_der().to_lower_hex_string())
+    }
+
+    pub fn sign_message_compact(
+        &self,
+        public_key: String,
+        message: String,
+    ) -> Result<String, SignMessageError> {
+        let wallet = self.get_wallet();
+        let public_key = PublicKey::from_str(&public_key)
+            .map_err(|_| SignMessageError::FailedToParsePublicKey)?;
+        let message = Message::from(signed_message_hash(&message));
+        let private_key = Self::private_key_of(&wallet, &public_key)?;
+        let sig = wallet
+            .secp_ctx()
+            .sign_ecdsa_recoverable(&message, &private_key);
+
+        // The header byte is 27 + recovery id, plus 4 if the public key is compressed.
+        let (recovery_id, compact) = sig.serialize_compact();
+        let mut header = 27 + recovery_id.to_i32() as u8;
+        if public_key.compressed {
+            header += 4;
+        }
+        let mut serialized = vec![header];
+        serialized.extend_from_slice(&compact);
+        use base64::prelude::*;
+        Ok(BASE64_STANDARD.encode(serialized))
+    }
+
+    /// The private key of the public key on the external keychain.
+    fn private_key_of(
+        wallet: &Wallet,
+        public_key: &PublicKey,
+    ) -> Result<secp256k1::SecretKey, SignMessageError> {
         let keychains: BTreeMap<_, _> = wallet.keychains().collect();
         let descriptor = keychains.get(&KeychainKind::External).unwrap();
         let script_buf = ScriptBuf::new_p2pkh(&public_key.pubkey_hash());
@@ -1386,9 +3971,7 @@ impl HdWallet {
                             .derivation_path
                             .extend(&[ChildNumber::from_normal_idx(index).unwrap()]);
                         let derived_xprv = xprv.xkey.derive_priv(wallet.secp_ctx(), &path).unwrap();
-                        let secp = wallet.secp_ctx();
-                        let sig = secp.sign_ecdsa(&message, &derived_xprv.private_key);
-                        Ok(sig.serialize_der().to_lower_hex_string())
+                        Ok(derived_xprv.private_key)
                     }
                     _ => {
                         unreachable!("Invalid private key type");
@@ -1420,203 +4003,1630 @@ impl HdWallet {
             Err(_) => Ok(false),
         }
     }
-}
 
-fn initialize_or_load_master_key(file_path: &str, network: tapyrus::Network) -> io::Result<Xpriv> {
-    if fs::metadata(file_path).is_ok() {
-        // File exists, read the private key
-        let mut file = File::open(file_path)?;
-        let mut xpriv_str = String::new();
-        file.read_to_string(&mut xpriv_str)?;
-        let xpriv = Xpriv::from_str(&xpriv_str).expect("Failed to parse Xpriv from file");
-        Ok(xpriv)
-    } else {
-        // File doesn't exist, generate Xpriv and persist
-        let seed: [u8; 32] = secp256k1::rand::thread_rng().gen();
-        let xpriv = Xpriv::new_master(network, &seed).unwrap();
-        let xpriv_str = xpriv.to_string();
-        let mut file = File::create(file_path)?;
-        file.write_all(xpriv_str.as_bytes())?;
-        Ok(xpriv)
+    pub fn verify_message(
+        &self,
+        address: String,
+        message: String,
+        signature: String,
+    ) -> Result<bool, VerifyMessageError> {
+        let script_pubkey = Address::from_str(&address)
+            .map_err(|_| VerifyMessageError::FailedToParseAddress {
+                address: address.clone(),
+            })?
+            .require_network(self.network)
+            .map_err(|_| VerifyMessageError::WrongNetworkAddress {
+                address: address.clone(),
+            })?
+            .script_pubkey()
+            .remove_color();
+        if !script_pubkey.is_p2pkh() {
+            return Err(VerifyMessageError::UnsupportedAddressType);
+        }
+
+        use base64::prelude::*;
+        let serialized = BASE64_STANDARD
+            .decode(&signature)
+            .map_err(|_| VerifyMessageError::FailedToParseSignature)?;
+        if serialized.len() != 65 || !(27..=34).contains(&serialized[0]) {
+            return Err(VerifyMessageError::FailedToParseSignature);
+        }
+        let header = serialized[0] - 27;
+        let recovery_id = RecoveryId::from_i32((header & 3) as i32)
+            .map_err(|_| VerifyMessageError::FailedToParseSignature)?;
+        let sig = RecoverableSignature::from_compact(&serialized[1..], recovery_id)
+            .map_err(|_| VerifyMessageError::FailedToParseSignature)?;
+
+        let message = Message::from(signed_message_hash(&message));
+        let secp = secp256k1::Secp256k1::verification_only();
+        match secp.recover_ecdsa(&message, &sig) {
+            Ok(inner) => {
+                let public_key = PublicKey {
+                    compressed: header & 4 != 0,
+                    inner,
+                };
+                Ok(ScriptBuf::new_p2pkh(&public_key.pubkey_hash()) == script_pubkey)
+            }
+            Err(_) => Ok(false),
+        }
     }
 }
 
-fn generate_master_key(network: Network) -> String {
-    let seed: [u8; 32] = secp256k1::rand::rngs::OsRng.gen();
-    Xpriv::new_master(network.into(), &seed)
-        .unwrap()
-        .to_string()
+/// Key-value data of this library. It is stored in the wallet db along with the tables of tdk.
+struct Metadata {
+    conn: Mutex<Connection>,
 }
 
-uniffi::include_scaffolding!("wallet");
-
-#[cfg(test)]
-mod test {
-    use crate::*;
-    use esplora_client::{self, BlockingClient, ScriptBuf};
-    use rand::random;
-    use std::hash::Hash;
-    use std::thread::sleep;
-    use std::time::Duration;
-    use std::{env, thread};
-    use tdk_chain::serde::{Deserialize, Serialize};
-    use tdk_esplora::esplora_client::Builder;
-    use tdk_testenv::{anyhow, tapyruscore_rpc::RpcApi, TestEnv};
-    use tdk_wallet::tapyrus::PubkeyHash;
+impl Metadata {
+    fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        // The db is also written by the tdk store through the other connection
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS ffi_metadata (key TEXT PRIMARY KEY NOT NULL, value TEXT NOT NULL)",
+            [],
+        )?;
+        // The contracts are read from the wallet store, so only the information which the wallet
+        // store does not have is kept.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS ffi_contract_info (contract_id TEXT PRIMARY KEY NOT NULL, encoding TEXT NOT NULL, deleted INTEGER NOT NULL DEFAULT 0)",
+            [],
+        )?;
+        Ok(Metadata {
+            conn: Mutex::new(conn),
+        })
+    }
 
-    fn db_file_path() -> String {
-        let mut temp_path = env::temp_dir();
-        let file_name = format!("tapyrus-wallet-{}.sqlite", random::<u32>());
-        temp_path.push(file_name);
-        temp_path.to_str().unwrap().to_string()
+    fn get(&self, key: &str) -> rusqlite::Result<Option<String>> {
+        let conn = self.conn.lock().expect("Failed to lock metadata");
+        conn.query_row(
+            "SELECT value FROM ffi_metadata WHERE key = ?1",
+            [key],
+            |row| row.get(0),
+        )
+        .optional()
     }
 
-    fn get_wallet() -> HdWallet {
-        let db_file_path = db_file_path();
+    fn set(&self, key: &str, value: &str) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().expect("Failed to lock metadata");
+        conn.execute(
+            "INSERT OR REPLACE INTO ffi_metadata (key, value) VALUES (?1, ?2)",
+            [key, value],
+        )?;
+        Ok(())
+    }
 
-        // testnet setting
-        let config = Config {
-            network_mode: Network::Prod,
-            network_id: 1939510133,
-            genesis_hash: "038b114875c2f78f5a2fd7d8549a905f38ea5faee6e29a3d79e547151d6bdd8a"
-                .to_string(),
-            esplora_url: Some("http://localhost:3001".to_string()),
-            esplora_user: None,
-            esplora_password: None,
-            electrum_domain: None,
-            electrum_port: None,
-            master_key_path: None,
-            master_key: Some("xprv9s21ZrQH143K3fYtYJZ5aLANmuode1z8g2AoQdwcxSrAwo6LzzGMSyNMLNw9d1q7TGPEc9d3bd2DjPaCJXR7pbWh1xuSFSRYsy1HHDeivek".to_string()),
-            db_file_path: Some(db_file_path),
-        };
-        HdWallet::new(Arc::new(config)).unwrap()
+    fn put_contract_info(&self, contract_id: &str, info: &ContractInfo) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().expect("Failed to lock metadata");
+        conn.execute(
+            "INSERT OR REPLACE INTO ffi_contract_info (contract_id, encoding, deleted) VALUES (?1, ?2, ?3)",
+            rusqlite::params![contract_id, info.encoding.as_str(), info.deleted],
+        )?;
+        Ok(())
     }
 
-    fn get_wallet_config_testenv(env: &TestEnv, master_key: Option<String>) -> Config {
-        let db_file_path = db_file_path();
-        Config {
-            network_mode: Network::Dev,
-            network_id: 1905960821,
-            genesis_hash: "aa71d030ac96eafa5cd4cb6dcbd8e8845c03b1a60641bf816c85e97bcf6bb8ea"
-                .to_string(),
-            esplora_url: Some(format!("http://{}", &env.electrsd.esplora_url.clone().unwrap())),
-            esplora_user: None,
-            esplora_password: None,
-            electrum_domain: None,
-            electrum_port: None,
-            master_key_path: None,
-            master_key: Some(master_key.unwrap_or("tprv8ZgxMBicQKsPeDdk6yMbK91PfeqepaeaKj1yGLRAGAac3yZEYS5Z6vMKu8rmybsyHWiEQ1JAZihfUC3DmGXq6H8279NVL7F8poWjVtVdFU9".to_string())),
-            db_file_path: Some(db_file_path),
-        }
+    fn get_contract_info(&self, contract_id: &str) -> rusqlite::Result<Option<ContractInfo>> {
+        let conn = self.conn.lock().expect("Failed to lock metadata");
+        conn.query_row(
+            "SELECT encoding, deleted FROM ffi_contract_info WHERE contract_id = ?1",
+            [contract_id],
+            ContractInfo::from_row,
+        )
+        .optional()
     }
 
-    fn get_wallet_config_testenv_electrum(env: &TestEnv, master_key: Option<String>) -> Config {
-        let db_file_path = db_file_path();
-        // Parse the electrum_url to extract domain and port
-        // electrum_url is in format "0.0.0.0:port" (listen address)
-        // We need to connect to "127.0.0.1:port"
-        let electrum_url = &env.electrsd.electrum_url;
-        let parts: Vec<&str> = electrum_url.split(':').collect();
-        let domain = "127.0.0.1".to_string();
-        let port: u16 = parts[1].parse().unwrap();
+    fn list_contract_info(&self) -> rusqlite::Result<HashMap<String, ContractInfo>> {
+        let conn = self.conn.lock().expect("Failed to lock metadata");
+        let mut stmt =
+            conn.prepare("SELECT contract_id, encoding, deleted FROM ffi_contract_info")?;
+        let info = stmt
+            .query_map([], |row| {
+                Ok((row.get(0)?, ContractInfo::from_row_at(row, 1)?))
+            })?
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+        Ok(info)
+    }
 
-        Config {
-            network_mode: Network::Dev,
-            network_id: 1905960821,
-            genesis_hash: "aa71d030ac96eafa5cd4cb6dcbd8e8845c03b1a60641bf816c85e97bcf6bb8ea"
-                .to_string(),
-            esplora_url: None,
-            esplora_user: None,
-            esplora_password: None,
-            electrum_domain: Some(domain),
-            electrum_port: Some(port),
-            master_key_path: None,
-            master_key: Some(master_key.unwrap_or("tprv8ZgxMBicQKsPeDdk6yMbK91PfeqepaeaKj1yGLRAGAac3yZEYS5Z6vMKu8rmybsyHWiEQ1JAZihfUC3DmGXq6H8279NVL7F8poWjVtVdFU9".to_string())),
-            db_file_path: Some(db_file_path),
-        }
+    fn delete_contract_info(&self, contract_id: &str) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().expect("Failed to lock metadata");
+        conn.execute(
+            "DELETE FROM ffi_contract_info WHERE contract_id = ?1",
+            [contract_id],
+        )?;
+        Ok(())
     }
+}
 
-    fn get_wallet_by_config(config: Config, env: &TestEnv, client: &BlockingClient) -> HdWallet {
-        let wallet = HdWallet::new(Arc::new(config)).unwrap();
+/// The information of the contract which the wallet store does not have.
+#[derive(Debug, Clone)]
+struct ContractInfo {
+    /// The encoding given by the user to return the contract in the same form.
+    encoding: ContractEncoding,
+    /// The wallet store can not remove the contract, so the deleted contract is hidden.
+    deleted: bool,
+}
 
-        wallet.full_sync().expect("Failed to sync");
-        let balance = wallet.balance(None).unwrap();
-        assert_eq!(balance, 0);
+impl ContractInfo {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Self::from_row_at(row, 0)
+    }
 
-        // Send TPC to the wallet for paying fee
-        let GetNewAddressResult {
-            address: address, ..
-        } = wallet.get_new_address(None).unwrap();
-        let address = Address::from_str(&address).unwrap().assume_checked();
-        let _ = env.tapyrusd.client.send_to_address(
-            &address,
-            Amount::from_tap(20000),
-            None,
-            None,
-            None,
-            None,
-            Some(1),
-            None,
-        );
-        wait_for_confirmation(&env, &client, 1);
-        wallet.sync().expect("Failed to sync");
-        let balance = wallet.balance(None).unwrap();
-        assert_eq!(balance, 20000);
+    fn from_row_at(row: &rusqlite::Row, index: usize) -> rusqlite::Result<Self> {
+        let encoding: String = row.get(index)?;
+        Ok(ContractInfo {
+            encoding: ContractEncoding::from_str(&encoding).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    index,
+                    rusqlite::types::Type::Text,
+                    e.into(),
+                )
+            })?,
+            deleted: row.get(index + 1)?,
+        })
+    }
+}
 
-        wallet
+/// The contract stored in the wallet store with the encoding kept in the metadata.
+#[derive(Debug, Clone)]
+struct StoredContract {
+    contract_id: String,
+    contract: Vec<u8>,
+    payment_base: String,
+    payable: bool,
+    encoding: ContractEncoding,
+}
+
+impl StoredContract {
+    /// The contract stored before the encoding was recorded is in UTF-8.
+    fn new(contract: tdk_wallet::chain::Contract, encoding: Option<ContractEncoding>) -> Self {
+        StoredContract {
+            contract_id: contract.contract_id,
+            contract: contract.contract,
+            payment_base: contract.payment_base.to_string(),
+            payable: contract.spendable,
+            encoding: encoding.unwrap_or(ContractEncoding::Utf8),
+        }
     }
+}
 
-    fn get_wallet_testenv(
-        env: &TestEnv,
-        client: &BlockingClient,
-        master_key: Option<String>,
-    ) -> HdWallet {
-        let config = get_wallet_config_testenv(env, master_key);
-        get_wallet_by_config(config, env, client)
+/// The contracts in the wallet store.
+fn wallet_contracts(wallet: &Wallet) -> Vec<tdk_wallet::chain::Contract> {
+    wallet.spk_index().contracts().values().cloned().collect()
+}
+
+/// The transferred outputs and their refund outputs checked by the trust layer refund.
+struct RefundTarget {
+    /// The color id of the outputs. None for TPC.
+    color_id: Option<ColorIdentifier>,
+    /// The uncolored script which the refund is paid to. None to accept any script of the wallet.
+    refund_script: Option<ScriptBuf>,
+}
+
+/// The backend client to look up the transactions for the trust layer refund and the contract
+/// discovery.
+enum RefundClient {
+    Esplora(esplora_client::BlockingClient),
+    Electrum(BdkElectrumClient<electrum_client::Client>),
+}
+
+impl RefundClient {
+    fn get_tx(&self, txid: &MalFixTxid) -> Result<Option<Transaction>, CheckTrustLayerRefundError> {
+        match self {
+            RefundClient::Esplora(client) => {
+                client
+                    .get_tx(txid)
+                    .map_err(|e| CheckTrustLayerRefundError::EsploraClientError {
+                        cause_description: e.to_string(),
+                    })
+            }
+            RefundClient::Electrum(client) => {
+                client.inner.transaction_get(txid).map(Some).map_err(|e| {
+                    CheckTrustLayerRefundError::ElectrumClientError {
+                        cause_description: e.to_string(),
+                    }
+                })
+            }
+        }
     }
 
-    fn wait_for_confirmation(
-        env: &TestEnv,
-        client: &BlockingClient,
-        count: usize,
-    ) -> anyhow::Result<()> {
-        let height = client.get_height().unwrap();
-        let _block_hashes = env.mine_blocks(count, None)?;
-        while client.get_height().unwrap() < height + (count as u32) {
-            sleep(Duration::from_millis(100))
+    /// Whether the script has any transactions.
+    fn has_history(&self, script: &ScriptBuf) -> Result<bool, CheckTrustLayerRefundError> {
+        match self {
+            RefundClient::Esplora(client) => client
+                .scripthash_txs(script, None)
+                .map(|txs| !txs.is_empty())
+                .map_err(|e| CheckTrustLayerRefundError::EsploraClientError {
+                    cause_description: e.to_string(),
+                }),
+            RefundClient::Electrum(client) => client
+                .inner
+                .script_get_history(script)
+                .map(|history| !history.is_empty())
+                .map_err(|e| CheckTrustLayerRefundError::ElectrumClientError {
+                    cause_description: e.to_string(),
+                }),
+        }
+    }
+
+    /// Find the transaction spending the output. The script is the script pubkey of the output.
+    fn spending_txid(
+        &self,
+        txid: &MalFixTxid,
+        index: u32,
+        script: &ScriptBuf,
+    ) -> Result<Option<MalFixTxid>, CheckTrustLayerRefundError> {
+        match self {
+            RefundClient::Esplora(client) => {
+                let output_status = client.get_output_status(txid, index as u64).map_err(|e| {
+                    CheckTrustLayerRefundError::EsploraClientError {
+                        cause_description: e.to_string(),
+                    }
+                })?;
+                Ok(output_status.and_then(|status| status.txid))
+            }
+            RefundClient::Electrum(client) => {
+                let map_err =
+                    |e: electrum_client::Error| CheckTrustLayerRefundError::ElectrumClientError {
+                        cause_description: e.to_string(),
+                    };
+                // Find transactions that spend from the output in the history of the script
+                for item in client.inner.script_get_history(script).map_err(map_err)? {
+                    if item.tx_hash == *txid {
+                        continue;
+                    }
+                    let tx = client
+                        .inner
+                        .transaction_get(&item.tx_hash)
+                        .map_err(map_err)?;
+                    let spends_output = tx.input.iter().any(|input| {
+                        input.previous_output.txid == *txid && input.previous_output.vout == index
+                    });
+                    if spends_output {
+                        return Ok(Some(item.tx_hash));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// The state of the wallet to find the changes by the sync.
+#[derive(Debug, Default)]
+struct WalletSnapshot {
+    /// The checkpoints of the local chain.
+    blocks: BTreeMap<u32, BlockHash>,
+    /// The confirmed height of the canonical transactions. None if unconfirmed.
+    txs: BTreeMap<MalFixTxid, Option<u32>>,
+    /// The balance for each color id. None is for TPC.
+    balances: BTreeMap<Option<String>, u64>,
+    /// The keychain and index of the addresses which have received outputs.
+    used: BTreeSet<(KeychainKind, u32)>,
+}
+
+impl WalletSnapshot {
+    fn new(wallet: &Wallet) -> Self {
+        let blocks = wallet
+            .latest_checkpoint()
+            .iter()
+            .map(|cp| (cp.height(), cp.hash()))
+            .collect();
+
+        let txs = wallet
+            .transactions()
+            .map(|tx| {
+                let height = match tx.chain_position {
+                    ChainPosition::Confirmed(anchor) => Some(anchor.confirmation_height),
+                    ChainPosition::Unconfirmed(_) => None,
+                };
+                (tx.tx_node.txid, height)
+            })
+            .collect();
+
+        let mut color_ids = vec![ColorIdentifier::default()];
+        let mut used = BTreeSet::new();
+        for output in wallet.list_output() {
+            if let Some(color_id) = output.txout.script_pubkey.color_id() {
+                if !color_ids.contains(&color_id) {
+                    color_ids.push(color_id);
+                }
+            }
+            used.insert((output.keychain, output.derivation_index));
+        }
+        let balances = color_ids
+            .into_iter()
+            .map(|color_id| {
+                let key = if color_id == ColorIdentifier::default() {
+                    None
+                } else {
+                    Some(color_id.to_string())
+                };
+                (key, wallet.balance(color_id).total().to_tap())
+            })
+            .collect();
+
+        WalletSnapshot {
+            blocks,
+            txs,
+            balances,
+            used,
+        }
+    }
+
+    /// Find the reorg between this snapshot and the other.
+    fn reorg(&self, after: &WalletSnapshot) -> Option<ReorgInfo> {
+        // The lowest block which is replaced or removed from the local chain
+        let disconnected_height = self
+            .blocks
+            .iter()
+            .find(|(height, hash)| after.blocks.get(*height) != Some(*hash))
+            .map(|(height, _)| *height)?;
+
+        let fork_height = self
+            .blocks
+            .range(..disconnected_height)
+            .rev()
+            .find(|(height, hash)| after.blocks.get(*height) == Some(*hash))
+            .map(|(height, _)| *height)
+            .unwrap_or(0);
+
+        let affected_txids = self
+            .txs
+            .iter()
+            .filter(|(txid, height)| match height {
+                Some(height) => {
+                    *height >= disconnected_height || after.txs.get(*txid) != Some(&Some(*height))
+                }
+                None => false,
+            })
+            .map(|(txid, _)| txid.to_string())
+            .collect();
+
+        Some(ReorgInfo {
+            fork_height,
+            affected_txids,
+        })
+    }
+
+    /// The events to change the wallet from this snapshot to the other.
+    fn events(&self, after: &WalletSnapshot) -> Vec<WalletEvent> {
+        let mut events = Vec::new();
+        for (txid, height) in &after.txs {
+            let txid_str = txid.to_string();
+            match (self.txs.get(txid), height) {
+                (None, _) => {
+                    events.push(WalletEvent::TransactionReceived {
+                        txid: txid_str.clone(),
+                    });
+                    if let Some(height) = height {
+                        events.push(WalletEvent::TransactionConfirmed {
+                            txid: txid_str,
+                            height: *height,
+                        });
+                    }
+                }
+                (Some(None), Some(height)) => events.push(WalletEvent::TransactionConfirmed {
+                    txid: txid_str,
+                    height: *height,
+                }),
+                // Confirmed in another block by the reorg
+                (Some(Some(old_height)), Some(height)) if old_height != height => {
+                    events.push(WalletEvent::TransactionConfirmed {
+                        txid: txid_str,
+                        height: *height,
+                    })
+                }
+                _ => {}
+            }
+        }
+        for txid in self.txs.keys() {
+            if !after.txs.contains_key(txid) {
+                events.push(WalletEvent::TransactionEvicted {
+                    txid: txid.to_string(),
+                });
+            }
+        }
+
+        let color_ids: BTreeSet<_> = self.balances.keys().chain(after.balances.keys()).collect();
+        for color_id in color_ids {
+            let old_balance = self.balances.get(color_id).copied().unwrap_or(0);
+            let new_balance = after.balances.get(color_id).copied().unwrap_or(0);
+            if old_balance != new_balance {
+                events.push(WalletEvent::BalanceChanged {
+                    color_id: color_id.clone(),
+                    old_balance,
+                    new_balance,
+                });
+            }
+        }
+
+        if let Some(reorg) = self.reorg(after) {
+            events.insert(0, WalletEvent::Reorg { reorg });
+        }
+        events
+    }
+}
+
+/// Background thread which runs the task repeatedly until it is stopped.
+/// The task returns the duration to wait before the next run, or None to finish the thread.
+struct Worker {
+    stop_sender: mpsc::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Worker {
+    fn spawn<F>(mut task: F) -> Self
+    where
+        F: FnMut() -> Option<Duration> + Send + 'static,
+    {
+        let (stop_sender, stop_receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Some(wait) = task() {
+                match stop_receiver.recv_timeout(wait) {
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    // Stopped or the worker was dropped
+                    _ => break,
+                }
+            }
+        });
+        Worker {
+            stop_sender,
+            handle,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Stop the thread and wait for the running task to finish.
+    fn stop(self) {
+        let _ = self.stop_sender.send(());
+        // Joining from the worker itself (e.g. a callback stops the worker) would deadlock
+        if self.handle.thread().id() != thread::current().id() {
+            let _ = self.handle.join();
+        }
+    }
+}
+
+/// Tracks the electrum subscriptions and turns the status changes into notifications.
+struct ElectrumNotifier {
+    url: String,
+    client: Option<electrum_client::Client>,
+    subscribed: HashSet<ScriptBuf>,
+    /// The confirmed height of the known transactions. None if unconfirmed.
+    known_txs: HashMap<MalFixTxid, Option<u32>>,
+    tip_height: Option<u32>,
+    initialized: bool,
+}
+
+enum Notification {
+    NewBlock { height: u32, block_hash: String },
+    NewTransaction { txid: String },
+    TransactionConfirmed { txid: String, height: u32 },
+}
+
+impl ElectrumNotifier {
+    fn new(url: String) -> Self {
+        ElectrumNotifier {
+            url,
+            client: None,
+            subscribed: HashSet::new(),
+            known_txs: HashMap::new(),
+            tip_height: None,
+            initialized: false,
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.subscribed.clear();
+    }
+
+    fn poll(
+        &mut self,
+        wallet: &HdWallet,
+        listener: &dyn NotificationListener,
+    ) -> Result<(), electrum_client::Error> {
+        let mut notifications = Vec::new();
+        let mut headers = Vec::new();
+
+        if self.client.is_none() {
+            let client = electrum_client::Client::new(&self.url)?;
+            headers.push(client.block_headers_subscribe()?);
+            self.client = Some(client);
+        }
+        let client = self.client.as_ref().unwrap();
+
+        // Receive pending notifications from the server
+        client.ping()?;
+        while let Some(header) = client.block_headers_pop()? {
+            headers.push(header);
+        }
+        for header in headers {
+            let height = header.height as u32;
+            if self.tip_height != Some(height) {
+                self.tip_height = Some(height);
+                notifications.push(Notification::NewBlock {
+                    height,
+                    block_hash: header.header.block_hash().to_string(),
+                });
+            }
+        }
+
+        // Subscribe newly revealed scripts, and find scripts whose status is changed
+        let mut changed = Vec::new();
+        for script in &self.subscribed {
+            if client.script_pop(script)?.is_some() {
+                changed.push(script.clone());
+            }
+        }
+        for script in wallet.watched_scripts() {
+            if !self.subscribed.contains(&script) {
+                client.script_subscribe(&script)?;
+                self.subscribed.insert(script.clone());
+                changed.push(script);
+            }
+        }
+
+        for script in changed {
+            for item in client.script_get_history(&script)? {
+                let height = if item.height > 0 {
+                    Some(item.height as u32)
+                } else {
+                    None
+                };
+                let txid = item.tx_hash.to_string();
+                match self.known_txs.insert(item.tx_hash, height) {
+                    None => {
+                        notifications.push(Notification::NewTransaction { txid: txid.clone() });
+                        if let Some(height) = height {
+                            notifications.push(Notification::TransactionConfirmed { txid, height });
+                        }
+                    }
+                    Some(None) => {
+                        if let Some(height) = height {
+                            notifications.push(Notification::TransactionConfirmed { txid, height });
+                        }
+                    }
+                    Some(Some(_)) => {}
+                }
+            }
+        }
+
+        // The first poll only records the current state
+        if !self.initialized {
+            self.initialized = true;
+            return Ok(());
+        }
+
+        for notification in notifications {
+            match notification {
+                Notification::NewBlock { height, block_hash } => {
+                    listener.on_new_block(height, block_hash)
+                }
+                Notification::NewTransaction { txid } => listener.on_new_transaction(txid),
+                Notification::TransactionConfirmed { txid, height } => {
+                    listener.on_transaction_confirmed(txid, height)
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn initialize_or_load_master_key(file_path: &str, network: tapyrus::Network) -> io::Result<Xpriv> {
+    if fs::metadata(file_path).is_ok() {
+        // File exists, read the private key
+        let mut file = File::open(file_path)?;
+        let mut xpriv_str = String::new();
+        file.read_to_string(&mut xpriv_str)?;
+        let xpriv = Xpriv::from_str(&xpriv_str).expect("Failed to parse Xpriv from file");
+        Ok(xpriv)
+    } else {
+        // File doesn't exist, generate Xpriv and persist
+        let seed: [u8; 32] = secp256k1::rand::thread_rng().gen();
+        let xpriv = Xpriv::new_master(network, &seed).unwrap();
+        let xpriv_str = xpriv.to_string();
+        let mut file = File::create(file_path)?;
+        file.write_all(xpriv_str.as_bytes())?;
+        Ok(xpriv)
+    }
+}
+
+/// Format the amount in tapyrus as the decimal TPC amount. e.g. 150000000 -> "1.5"
+fn format_tpc_amount(amount: u64) -> String {
+    let tpc = amount / 100_000_000;
+    let fraction = amount % 100_000_000;
+    if fraction == 0 {
+        return tpc.to_string();
+    }
+    let fraction = format!("{:08}", fraction);
+    format!("{}.{}", tpc, fraction.trim_end_matches('0'))
+}
+
+/// Parse the decimal TPC amount into the amount in tapyrus. e.g. "1.5" -> 150000000
+fn parse_tpc_amount(amount: &str) -> Option<u64> {
+    let (tpc, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    let is_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    if (tpc.is_empty() && fraction.is_empty())
+        || fraction.len() > 8
+        || !is_digits(tpc)
+        || !is_digits(fraction)
+    {
+        return None;
+    }
+    let tpc: u64 = if tpc.is_empty() { 0 } else { tpc.parse().ok()? };
+    let fraction: u64 = format!("{:0<8}", fraction).parse().ok()?;
+    tpc.checked_mul(100_000_000)?.checked_add(fraction)
+}
+
+/// Percent-encode the characters other than the unreserved characters in RFC 3986.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Decode the percent-encoded string. Return None if the encoding is malformed.
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Serialize the JSON value by the JSON Canonicalization Scheme (RFC 8785).
+/// The contract committed by the pay to contract address must not depend on the serializer,
+/// so the keys, numbers and strings are serialized here instead of serde_json.
+fn canonicalize_json(value: &serde_json::Value, out: &mut String) -> Result<(), String> {
+    match value {
+        serde_json::Value::Null => out.push_str("null"),
+        serde_json::Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        serde_json::Value::Number(n) => {
+            let n = n
+                .as_f64()
+                .ok_or_else(|| format!("Unsupported number: {}", n))?;
+            out.push_str(&canonical_json_number(n)?);
+        }
+        serde_json::Value::String(s) => canonicalize_json_string(s, out),
+        serde_json::Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonicalize_json(value, out)?;
+            }
+            out.push(']');
+        }
+        serde_json::Value::Object(map) => {
+            // The keys are sorted by their UTF-16 code units
+            let mut entries = map.iter().collect::<Vec<_>>();
+            entries.sort_by_cached_key(|(key, _)| key.encode_utf16().collect::<Vec<_>>());
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonicalize_json_string(key, out);
+                out.push(':');
+                canonicalize_json(value, out)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+/// Serialize the string in the same way as ECMAScript JSON.stringify.
+fn canonicalize_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{08}' => out.push_str("\\b"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\u{0c}' => out.push_str("\\f"),
+            '\r' => out.push_str("\\r"),
+            c if c < '\u{20}' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Serialize the number in the same way as ECMAScript Number.prototype.toString.
+fn canonical_json_number(n: f64) -> Result<String, String> {
+    if !n.is_finite() {
+        return Err(format!("Unsupported number: {}", n));
+    }
+    if n == 0.0 {
+        return Ok("0".to_string());
+    }
+    // The shortest digits which round trip and the exponent of the first digit
+    let formatted = format!("{:e}", n.abs());
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let digits = mantissa.replace('.', "");
+    let exponent: i32 = exponent.parse().unwrap();
+    let k = digits.len() as i32;
+    let point = exponent + 1;
+
+    let mut s = String::new();
+    if n < 0.0 {
+        s.push('-');
+    }
+    if k <= point && point <= 21 {
+        s.push_str(&digits);
+        s.push_str(&"0".repeat((point - k) as usize));
+    } else if 0 < point && point <= 21 {
+        s.push_str(&digits[..point as usize]);
+        s.push('.');
+        s.push_str(&digits[point as usize..]);
+    } else if -6 < point && point <= 0 {
+        s.push_str("0.");
+        s.push_str(&"0".repeat((-point) as usize));
+        s.push_str(&digits);
+    } else {
+        s.push_str(&digits[..1]);
+        if k > 1 {
+            s.push('.');
+            s.push_str(&digits[1..]);
+        }
+        s.push('e');
+        s.push(if point - 1 < 0 { '-' } else { '+' });
+        s.push_str(&(point - 1).abs().to_string());
+    }
+    Ok(s)
+}
+
+/// The commitment of the pay to contract protocol, SHA256(payment_base || contract).
+/// The public key of the pay to contract address is payment_base + commitment * G.
+fn p2c_commitment(payment_base: &PublicKey, contract: &[u8]) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&payment_base.to_bytes());
+    engine.input(contract);
+    sha256::Hash::from_engine(engine)
+}
+
+/// The digest of the Bitcoin signed message format,
+/// SHA256d(varint(len(prefix)) || prefix || varint(len(message)) || message).
+fn signed_message_hash(message: &str) -> sha256d::Hash {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&serialize(&VarInt(SIGNED_MESSAGE_PREFIX.len() as u64)));
+    engine.input(SIGNED_MESSAGE_PREFIX.as_bytes());
+    engine.input(&serialize(&VarInt(message.len() as u64)));
+    engine.input(message.as_bytes());
+    sha256d::Hash::from_engine(engine)
+}
+
+/// The HMAC-SHA256 of the contract bundle without the hmac field.
+/// The key is derived from the master key, so only the wallet restored from the same master key
+/// can import the bundle.
+fn contract_bundle_hmac(
+    wallet: &Wallet,
+    bundle: &serde_json::Value,
+) -> Result<Hmac<sha256::Hash>, String> {
+    let signers = wallet.get_signers(KeychainKind::External);
+    let key_map = signers.as_key_map(wallet.secp_ctx());
+    let master_key = match key_map.values().next() {
+        Some(DescriptorSecretKey::XPrv(xprv)) => xprv.xkey.private_key.secret_bytes(),
+        Some(_) => return Err("Invalid private key type".to_string()),
+        None => return Err("No private key".to_string()),
+    };
+    let mut key_engine = HmacEngine::<sha256::Hash>::new(CONTRACT_BUNDLE_KEY_TAG);
+    key_engine.input(&master_key);
+    let key = Hmac::<sha256::Hash>::from_engine(key_engine);
+
+    // The bundle is canonicalized, so the MAC does not depend on the serializer
+    let mut canonical = String::new();
+    canonicalize_json(bundle, &mut canonical)?;
+    let mut engine = HmacEngine::<sha256::Hash>::new(&key.to_byte_array());
+    engine.input(canonical.as_bytes());
+    Ok(Hmac::from_engine(engine))
+}
+
+/// Compare the bytes in the time which does not depend on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Attribute the refund to the outputs spent by the refund transaction up to their amounts in
+/// order. The excess is attributed to the last one.
+fn attribute_refund(refund: u64, amounts: &[u64]) -> Vec<u64> {
+    let mut remaining = refund;
+    amounts
+        .iter()
+        .enumerate()
+        .map(|(i, amount)| {
+            let attributed = if i + 1 == amounts.len() {
+                remaining
+            } else {
+                remaining.min(*amount)
+            };
+            remaining -= attributed;
+            attributed
+        })
+        .collect()
+}
+
+/// Find the network which the address is valid for.
+fn address_network(address: &Address<NetworkUnchecked>) -> Option<tapyrus::Network> {
+    [tapyrus::Network::Prod, tapyrus::Network::Dev]
+        .into_iter()
+        .find(|network| address.is_valid_for_network(*network))
+}
+
+fn generate_master_key(network: Network) -> String {
+    let seed: [u8; 32] = secp256k1::rand::rngs::OsRng.gen();
+    Xpriv::new_master(network.into(), &seed)
+        .unwrap()
+        .to_string()
+}
+
+/// Parse the address of any supported network and return the network and the script pubkey.
+fn parse_any_network_address(
+    address: &str,
+) -> Result<(tapyrus::Network, ScriptBuf), ConvertAddressError> {
+    let unchecked =
+        Address::from_str(address).map_err(|_| ConvertAddressError::FailedToParseAddress {
+            address: address.to_string(),
+        })?;
+    let network =
+        address_network(&unchecked).ok_or_else(|| ConvertAddressError::FailedToParseAddress {
+            address: address.to_string(),
+        })?;
+    Ok((network, unchecked.assume_checked().script_pubkey()))
+}
+
+fn add_color_to_address(address: String, color_id: String) -> Result<String, ConvertAddressError> {
+    let color_id =
+        ColorIdentifier::from_str(&color_id).map_err(|_| ConvertAddressError::InvalidColorId)?;
+    let (network, script) = parse_any_network_address(&address)?;
+    if script.is_colored() {
+        return Err(ConvertAddressError::AlreadyColored { address });
+    }
+    if !script.is_p2pkh() && !script.is_p2sh() {
+        return Err(ConvertAddressError::UnsupportedAddressType { address });
+    }
+    let colored =
+        script
+            .add_color(color_id)
+            .map_err(|_| ConvertAddressError::UnsupportedAddressType {
+                address: address.clone(),
+            })?;
+    Address::from_script(&colored, network)
+        .map(|a| a.to_string())
+        .map_err(|_| ConvertAddressError::UnsupportedAddressType { address })
+}
+
+fn remove_color_from_address(address: String) -> Result<UncoloredAddress, ConvertAddressError> {
+    let (network, script) = parse_any_network_address(&address)?;
+    let color_id = script
+        .color_id()
+        .ok_or_else(|| ConvertAddressError::NotColored {
+            address: address.clone(),
+        })?;
+    let base_address = Address::from_script(&script.remove_color(), network)
+        .map_err(|_| ConvertAddressError::UnsupportedAddressType { address })?;
+    Ok(UncoloredAddress {
+        address: base_address.to_string(),
+        color_id: color_id.to_string(),
+    })
+}
+
+uniffi::include_scaffolding!("wallet");
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use esplora_client::{self, BlockingClient, ScriptBuf};
+    use rand::random;
+    use std::hash::Hash;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use std::{env, thread};
+    use tdk_chain::serde::{Deserialize, Serialize};
+    use tdk_esplora::esplora_client::Builder;
+    use tdk_testenv::{anyhow, tapyruscore_rpc::RpcApi, TestEnv};
+    use tdk_wallet::tapyrus::PubkeyHash;
+
+    fn db_file_path() -> String {
+        let mut temp_path = env::temp_dir();
+        let file_name = format!("tapyrus-wallet-{}.sqlite", random::<u32>());
+        temp_path.push(file_name);
+        temp_path.to_str().unwrap().to_string()
+    }
+
+    fn get_wallet() -> HdWallet {
+        get_wallet_with_master_key("xprv9s21ZrQH143K3fYtYJZ5aLANmuode1z8g2AoQdwcxSrAwo6LzzGMSyNMLNw9d1q7TGPEc9d3bd2DjPaCJXR7pbWh1xuSFSRYsy1HHDeivek".to_string())
+    }
+
+    fn get_wallet_with_master_key(master_key: String) -> HdWallet {
+        let db_file_path = db_file_path();
+
+        // testnet setting
+        let config = Config {
+            network_mode: Network::Prod,
+            network_id: 1939510133,
+            genesis_hash: "038b114875c2f78f5a2fd7d8549a905f38ea5faee6e29a3d79e547151d6bdd8a"
+                .to_string(),
+            esplora_url: Some("http://localhost:3001".to_string()),
+            esplora_user: None,
+            esplora_password: None,
+            electrum_domain: None,
+            electrum_port: None,
+            master_key_path: None,
+            master_key: Some(master_key),
+            db_file_path: Some(db_file_path),
+            esplora_headers: None,
+            esplora_token_provider: None,
+        };
+        HdWallet::new(Arc::new(config)).unwrap()
+    }
+
+    fn get_wallet_config_testenv(env: &TestEnv, master_key: Option<String>) -> Config {
+        let db_file_path = db_file_path();
+        Config {
+            network_mode: Network::Dev,
+            network_id: 1905960821,
+            genesis_hash: "aa71d030ac96eafa5cd4cb6dcbd8e8845c03b1a60641bf816c85e97bcf6bb8ea"
+                .to_string(),
+            esplora_url: Some(format!("http://{}", &env.electrsd.esplora_url.clone().unwrap())),
+            esplora_user: None,
+            esplora_password: None,
+            electrum_domain: None,
+            electrum_port: None,
+            master_key_path: None,
+            master_key: Some(master_key.unwrap_or("tprv8ZgxMBicQKsPeDdk6yMbK91PfeqepaeaKj1yGLRAGAac3yZEYS5Z6vMKu8rmybsyHWiEQ1JAZihfUC3DmGXq6H8279NVL7F8poWjVtVdFU9".to_string())),
+            db_file_path: Some(db_file_path),
+            esplora_headers: None,
+            esplora_token_provider: None,
+        }
+    }
+
+    fn get_wallet_config_testenv_electrum(env: &TestEnv, master_key: Option<String>) -> Config {
+        let db_file_path = db_file_path();
+        // Parse the electrum_url to extract domain and port
+        // electrum_url is in format "0.0.0.0:port" (listen address)
+        // We need to connect to "127.0.0.1:port"
+        let electrum_url = &env.electrsd.electrum_url;
+        let parts: Vec<&str> = electrum_url.split(':').collect();
+        let domain = "127.0.0.1".to_string();
+        let port: u16 = parts[1].parse().unwrap();
+
+        Config {
+            network_mode: Network::Dev,
+            network_id: 1905960821,
+            genesis_hash: "aa71d030ac96eafa5cd4cb6dcbd8e8845c03b1a60641bf816c85e97bcf6bb8ea"
+                .to_string(),
+            esplora_url: None,
+            esplora_user: None,
+            esplora_password: None,
+            electrum_domain: Some(domain),
+            electrum_port: Some(port),
+            master_key_path: None,
+            master_key: Some(master_key.unwrap_or("tprv8ZgxMBicQKsPeDdk6yMbK91PfeqepaeaKj1yGLRAGAac3yZEYS5Z6vMKu8rmybsyHWiEQ1JAZihfUC3DmGXq6H8279NVL7F8poWjVtVdFU9".to_string())),
+            db_file_path: Some(db_file_path),
+            esplora_headers: None,
+            esplora_token_provider: None,
+        }
+    }
+
+    fn get_wallet_by_config(config: Config, env: &TestEnv, client: &BlockingClient) -> HdWallet {
+        let wallet = HdWallet::new(Arc::new(config)).unwrap();
+
+        wallet.full_sync().expect("Failed to sync");
+        let status = wallet.sync_status().unwrap();
+        assert!(status.full_scan_completed);
+        assert!(status.last_sync_time.is_some());
+        assert_eq!(
+            status.local_tip.height,
+            wallet.get_backend_tip().unwrap().height
+        );
+        let balance = wallet.balance(None).unwrap();
+        assert_eq!(balance, 0);
+
+        // Send TPC to the wallet for paying fee
+        let GetNewAddressResult {
+            address: address, ..
+        } = wallet.get_new_address(None).unwrap();
+        let address = Address::from_str(&address).unwrap().assume_checked();
+        let _ = env.tapyrusd.client.send_to_address(
+            &address,
+            Amount::from_tap(20000),
+            None,
+            None,
+            None,
+            None,
+            Some(1),
+            None,
+        );
+        wait_for_confirmation(&env, &client, 1);
+        let result = wallet.sync().expect("Failed to sync");
+        assert_eq!(result.new_transactions, 1);
+        assert_eq!(result.newly_used_addresses, 1);
+        assert_eq!(result.last_revealed_external_index, Some(0));
+        assert!(result.reorg.is_none());
+        let balance = wallet.balance(None).unwrap();
+        assert_eq!(balance, 20000);
+
+        wallet
+    }
+
+    fn get_wallet_testenv(
+        env: &TestEnv,
+        client: &BlockingClient,
+        master_key: Option<String>,
+    ) -> HdWallet {
+        let config = get_wallet_config_testenv(env, master_key);
+        get_wallet_by_config(config, env, client)
+    }
+
+    fn wait_for_confirmation(
+        env: &TestEnv,
+        client: &BlockingClient,
+        count: usize,
+    ) -> anyhow::Result<()> {
+        let height = client.get_height().unwrap();
+        let _block_hashes = env.mine_blocks(count, None)?;
+        while client.get_height().unwrap() < height + (count as u32) {
+            sleep(Duration::from_millis(100))
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_new_address() {
+        let wallet = get_wallet();
+        let GetNewAddressResult {
+            address,
+            public_key,
+        } = wallet.get_new_address(None).unwrap();
+        assert_eq!(address.len(), 34, "Address should be 34 characters long");
+        let public_key = PublicKey::from_str(&public_key).unwrap();
+        assert_eq!(
+            address,
+            Address::p2pkh(&public_key, wallet.network).to_string()
+        );
+
+        let color_id = ColorIdentifier::from_str(
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46",
+        )
+        .unwrap();
+        let GetNewAddressResult {
+            address,
+            public_key,
+        } = wallet.get_new_address(Some(color_id.to_string())).unwrap();
+        assert_eq!(address.len(), 78, "Address should be 78 characters long");
+        let public_key = PublicKey::from_str(&public_key).unwrap();
+        let spk = ScriptBuf::new_cp2pkh(&color_id, &PubkeyHash::from(public_key));
+        let expected = Address::from_script(&spk, wallet.network)
+            .unwrap()
+            .to_string();
+        assert_eq!(address, expected);
+    }
+
+    #[test]
+    fn test_list_addresses_and_peek_address() {
+        let wallet = get_wallet();
+        assert!(wallet.list_addresses(Keychain::External).is_empty());
+
+        let first = wallet.get_new_address(None).unwrap();
+        let second = wallet.get_new_address(None).unwrap();
+
+        // Peeking does not reveal the address
+        let peeked = wallet.peek_address(Keychain::External, 5, None).unwrap();
+        let addresses = wallet.list_addresses(Keychain::External);
+        assert_eq!(addresses.len(), 2);
+        assert!(wallet.list_addresses(Keychain::Internal).is_empty());
+
+        assert_eq!(addresses[0].index, 0);
+        assert_eq!(addresses[0].address, first.address);
+        assert_eq!(addresses[0].public_key, first.public_key);
+        assert_eq!(addresses[1].index, 1);
+        assert_eq!(addresses[1].address, second.address);
+        assert!(!addresses[1].used);
+        assert!(addresses[1].received.is_empty());
+
+        let next = wallet.get_new_address(None).unwrap();
+        assert_ne!(next.address, peeked.address);
+        assert_eq!(
+            wallet
+                .peek_address(Keychain::External, 0, None)
+                .unwrap()
+                .address,
+            first.address
+        );
+
+        let color_id =
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string();
+        let colored = wallet
+            .peek_address(Keychain::External, 0, Some(color_id))
+            .unwrap();
+        assert_eq!(colored.public_key, first.public_key);
+        assert_eq!(colored.address.len(), 78);
+
+        assert!(matches!(
+            wallet.peek_address(Keychain::External, 0x80000000, None),
+            Err(PeekAddressError::InvalidIndex)
+        ));
+        assert!(matches!(
+            wallet.peek_address(Keychain::External, 0, Some("invalid".to_string())),
+            Err(PeekAddressError::InvalidColorId)
+        ));
+    }
+
+    #[test]
+    fn test_get_unused_address() {
+        let wallet = get_wallet();
+        let first = wallet.get_unused_address(None).unwrap();
+        assert_eq!(
+            wallet.get_unused_address(None).unwrap().address,
+            first.address
+        );
+        assert_eq!(wallet.list_addresses(Keychain::External).len(), 1);
+
+        // Marked address is not returned
+        assert!(wallet.mark_used(Keychain::External, 0));
+        let second = wallet.get_unused_address(None).unwrap();
+        assert_ne!(second.address, first.address);
+
+        // Unmarked address is returned again
+        assert!(wallet.unmark_used(Keychain::External, 0));
+        assert_eq!(
+            wallet.get_unused_address(None).unwrap().address,
+            first.address
+        );
+
+        let color_id =
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string();
+        let colored = wallet.get_unused_address(Some(color_id)).unwrap();
+        assert_eq!(colored.public_key, first.public_key);
+        assert!(matches!(
+            wallet.get_unused_address(Some("invalid".to_string())),
+            Err(GetNewAddressError::InvalidColorId)
+        ));
+    }
+
+    #[test]
+    fn test_get_change_address() {
+        let wallet = get_wallet();
+        let change = wallet.get_change_address(None).unwrap();
+        assert_ne!(
+            change.address,
+            wallet.get_new_address(None).unwrap().address
+        );
+
+        let addresses = wallet.list_addresses(Keychain::Internal);
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(addresses[0].address, change.address);
+        assert_eq!(
+            wallet
+                .peek_address(Keychain::Internal, 0, None)
+                .unwrap()
+                .address,
+            change.address
+        );
+    }
+
+    #[test]
+    fn test_transfer_with_options_invalid_change_address() {
+        let wallet = get_wallet();
+        let options = TransferOptions {
+            change_address: Some("invalid".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            wallet.transfer_with_options(vec![], vec![], options),
+            Err(TransferError::FailedToParseAddress { address }) if address == "invalid"
+        ));
+
+        let colored_address = add_color_to_address(
+            "1111111111111111111114oLvT2".to_string(),
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string(),
+        )
+        .unwrap();
+        let options = TransferOptions {
+            change_address: Some(colored_address.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            wallet.transfer_with_options(vec![], vec![], options),
+            Err(TransferError::InvalidChangeAddress { address }) if address == colored_address
+        ));
+
+        // P2SH address
+        let options = TransferOptions {
+            colored_change_address: Some("31h1vYVSYuKP6AhS86fbRdMw9XHieotbST".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            wallet.transfer_with_options(vec![], vec![], options),
+            Err(TransferError::InvalidChangeAddress { address })
+                if address == "31h1vYVSYuKP6AhS86fbRdMw9XHieotbST"
+        ));
+    }
+
+    #[test]
+    fn test_parse_address() {
+        let wallet = get_wallet();
+        let GetNewAddressResult { address, .. } = wallet.get_new_address(None).unwrap();
+        let details = wallet.parse_address(address.clone()).unwrap();
+        assert_eq!(details.network, Network::Prod);
+        assert_eq!(details.address_type, AddressType::P2pkh);
+        assert_eq!(details.color_id, None);
+        assert!(details.is_mine);
+        let expected = Address::from_str(&address)
+            .unwrap()
+            .assume_checked()
+            .script_pubkey();
+        assert_eq!(details.script_pubkey, expected.to_hex_string());
+
+        let color_id =
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string();
+        let GetNewAddressResult { address, .. } =
+            wallet.get_new_address(Some(color_id.clone())).unwrap();
+        let details = wallet.parse_address(address).unwrap();
+        assert_eq!(details.address_type, AddressType::Cp2pkh);
+        assert_eq!(details.color_id, Some(color_id.clone()));
+        assert!(details.is_mine);
+
+        // The uncolored address of the wallet colored with any color belongs to the wallet
+        let GetNewAddressResult { address, .. } = wallet.get_new_address(None).unwrap();
+        let colored = add_color_to_address(address, color_id).unwrap();
+        assert!(wallet.parse_address(colored).unwrap().is_mine);
+
+        let details = wallet
+            .parse_address("mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string())
+            .unwrap();
+        assert_eq!(details.network, Network::Dev);
+        assert_eq!(details.address_type, AddressType::P2pkh);
+        assert!(!details.is_mine);
+
+        assert_eq!(
+            wallet.parse_address("invalid".to_string()),
+            Err(ParseAddressError::FailedToParseAddress {
+                address: "invalid".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_add_and_remove_color_of_address() {
+        let address = "mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string();
+        let color_id =
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string();
+
+        let colored = add_color_to_address(address.clone(), color_id.clone()).unwrap();
+        assert_ne!(colored, address);
+        assert_eq!(
+            add_color_to_address(colored.clone(), color_id.clone()),
+            Err(ConvertAddressError::AlreadyColored {
+                address: colored.clone()
+            })
+        );
+
+        let uncolored = remove_color_from_address(colored).unwrap();
+        assert_eq!(uncolored.address, address);
+        assert_eq!(uncolored.color_id, color_id);
+        assert_eq!(
+            remove_color_from_address(address.clone()),
+            Err(ConvertAddressError::NotColored { address })
+        );
+
+        assert_eq!(
+            add_color_to_address("invalid".to_string(), color_id),
+            Err(ConvertAddressError::FailedToParseAddress {
+                address: "invalid".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_tpc_amount() {
+        assert_eq!(format_tpc_amount(0), "0");
+        assert_eq!(format_tpc_amount(150_000_000), "1.5");
+        assert_eq!(format_tpc_amount(1), "0.00000001");
+        assert_eq!(parse_tpc_amount("1.5"), Some(150_000_000));
+        assert_eq!(parse_tpc_amount(".5"), Some(50_000_000));
+        assert_eq!(parse_tpc_amount("10"), Some(1_000_000_000));
+        assert_eq!(parse_tpc_amount("0.000000001"), None);
+        assert_eq!(parse_tpc_amount("1e3"), None);
+        assert_eq!(parse_tpc_amount("."), None);
+        assert_eq!(parse_tpc_amount("-1"), None);
+    }
+
+    #[test]
+    fn test_payment_uri() {
+        let wallet = get_wallet();
+        let address = "1111111111111111111114oLvT2".to_string();
+
+        let request = PaymentRequest {
+            address: address.clone(),
+            amount: Some(150_000_000),
+            label: Some("Alice & Bob".to_string()),
+            message: Some("ありがとう".to_string()),
+            ..Default::default()
+        };
+        let uri = wallet.encode_payment_uri(request.clone()).unwrap();
+        assert!(uri.starts_with(&format!(
+            "tapyrus:{}?amount=1.5&label=Alice%20%26%20Bob&message=%E3%81%82",
+            address
+        )));
+        assert_eq!(wallet.decode_payment_uri(uri).unwrap(), request);
+
+        // The color id is taken from the colored address and the amount is in token units
+        let color_id =
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string();
+        let colored = add_color_to_address(address.clone(), color_id.clone()).unwrap();
+        let request = wallet
+            .decode_payment_uri(format!("TAPYRUS:{}?amount=100", colored))
+            .unwrap();
+        assert_eq!(request.amount, Some(100));
+        assert_eq!(request.color_id, Some(color_id.clone()));
+
+        // The request for the colored address without color_id
+        let request = PaymentRequest {
+            address: colored.clone(),
+            amount: Some(100),
+            ..Default::default()
+        };
+        let uri = wallet.encode_payment_uri(request.clone()).unwrap();
+        assert_eq!(
+            uri,
+            format!("tapyrus:{}?amount=100&colorid={}", colored, color_id)
+        );
+        assert_eq!(
+            wallet.decode_payment_uri(uri).unwrap(),
+            PaymentRequest {
+                color_id: Some(color_id),
+                ..request
+            }
+        );
+
+        assert!(matches!(
+            wallet.decode_payment_uri(format!("bitcoin:{}", address)),
+            Err(PaymentUriError::InvalidUri { .. })
+        ));
+        assert_eq!(
+            wallet.decode_payment_uri(format!("tapyrus:{}?amount=1.000000001", address)),
+            Err(PaymentUriError::InvalidAmount {
+                amount: "1.000000001".to_string()
+            })
+        );
+        assert_eq!(
+            wallet.decode_payment_uri(format!("tapyrus:{}?req-unknown=1", address)),
+            Err(PaymentUriError::UnknownRequiredParameter {
+                name: "req-unknown".to_string()
+            })
+        );
+        // Address of the development network
+        assert_eq!(
+            wallet.decode_payment_uri("tapyrus:mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string()),
+            Err(PaymentUriError::WrongNetworkAddress {
+                address: "mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string()
+            })
+        );
+        assert!(matches!(
+            wallet.decode_payment_uri(format!("tapyrus:{}?paymentbase=02", address)),
+            Err(PaymentUriError::InvalidContract { .. })
+        ));
+    }
+
+    #[test]
+    fn test_payment_uri_with_contract() {
+        let wallet = get_wallet();
+        let GetNewAddressResult { public_key, .. } = wallet.get_new_address(None).unwrap();
+
+        // The contract is committed in the canonical form of the JSON document
+        let contract = r#"{ "b": 1, "a": "x" }"#.to_string();
+        let address = wallet
+            .calc_p2c_address(
+                public_key.clone(),
+                r#"{"a":"x","b":1}"#.to_string(),
+                None,
+                None,
+            )
+            .unwrap();
+        let request = PaymentRequest {
+            address,
+            payment_base: Some(public_key.clone()),
+            contract: Some(contract),
+            contract_encoding: Some(ContractEncoding::Json),
+            ..Default::default()
+        };
+        let uri = wallet.encode_payment_uri(request.clone()).unwrap();
+        assert!(uri.ends_with("&contractencoding=json"));
+        assert_eq!(wallet.decode_payment_uri(uri).unwrap(), request);
+
+        // The contract in hex is not committed as the UTF-8 bytes of the hex string
+        let address = wallet
+            .calc_p2c_address(
+                public_key.clone(),
+                "0102".to_string(),
+                None,
+                Some(ContractEncoding::Hex),
+            )
+            .unwrap();
+        let request = PaymentRequest {
+            address,
+            payment_base: Some(public_key),
+            contract: Some("0102".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            wallet.encode_payment_uri(request.clone()),
+            Err(PaymentUriError::InvalidContract { .. })
+        ));
+        let request = PaymentRequest {
+            contract_encoding: Some(ContractEncoding::Hex),
+            ..request
+        };
+        assert!(wallet.encode_payment_uri(request).is_ok());
+
+        assert!(matches!(
+            wallet.decode_payment_uri(
+                "tapyrus:1111111111111111111114oLvT2?contractencoding=unknown".to_string()
+            ),
+            Err(PaymentUriError::InvalidContract { .. })
+        ));
+    }
+
+    #[test]
+    fn test_canonicalize_json() {
+        // The test vectors of RFC 8785
+        let canonical = ContractEncoding::Json
+            .decode(
+                r#"{
+                  "numbers": [333333333.33333329, 1E30, 4.50, 2e-3, 0.000000000000000000000000001],
+                  "string": "\u20ac$\u000F\u000aA'\u0042\u0022\u005c\\\"\/",
+                  "literals": [null, true, false]
+                }"#,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(canonical).unwrap(),
+            r#"{"literals":[null,true,false],"numbers":[333333333.3333333,1e+30,4.5,0.002,1e-27],"string":"€$\u000f\nA'B\"\\\\\"/"}"#
+        );
+
+        // The keys are sorted by UTF-16 code units
+        let canonical = ContractEncoding::Json
+            .decode(
+                r#"{"\u20ac":"Euro Sign","\r":"Carriage Return","\ufb33":"Hebrew Letter Dalet With Dagesh","1":"One","\ud83d\ude00":"Emoji: Grinning Face","\u0080":"Control","\u00f6":"Latin Small Letter O With Diaeresis"}"#,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(canonical).unwrap(),
+            "{\"\\r\":\"Carriage Return\",\"1\":\"One\",\"\u{80}\":\"Control\",\"ö\":\"Latin Small Letter O With Diaeresis\",\"€\":\"Euro Sign\",\"😀\":\"Emoji: Grinning Face\",\"דּ\":\"Hebrew Letter Dalet With Dagesh\"}"
+        );
+
+        let numbers = [
+            (0.0, "0"),
+            (-0.0, "0"),
+            (1.0, "1"),
+            (-1.5, "-1.5"),
+            (1e21, "1e+21"),
+            (1e20, "100000000000000000000"),
+            (0.000001, "0.000001"),
+            (0.0000001, "1e-7"),
+            (9007199254740991.0, "9007199254740991"),
+            (5e-324, "5e-324"),
+            (1.7976931348623157e308, "1.7976931348623157e+308"),
+        ];
+        for (n, expected) in numbers {
+            assert_eq!(canonical_json_number(n).unwrap(), expected);
         }
-        Ok(())
     }
 
     #[test]
-    fn test_get_new_address() {
+    fn test_attribute_refund() {
+        assert_eq!(attribute_refund(10, &[10]), vec![10]);
+        assert_eq!(attribute_refund(15, &[10, 10]), vec![10, 5]);
+        assert_eq!(attribute_refund(5, &[10, 10]), vec![5, 0]);
+        assert_eq!(attribute_refund(25, &[10, 10]), vec![10, 15]);
+        assert_eq!(attribute_refund(0, &[10, 10]), vec![0, 0]);
+        assert!(attribute_refund(10, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_check_trust_layer_refunds_error() {
         let wallet = get_wallet();
-        let GetNewAddressResult {
-            address,
-            public_key,
-        } = wallet.get_new_address(None).unwrap();
-        assert_eq!(address.len(), 34, "Address should be 34 characters long");
-        let public_key = PublicKey::from_str(&public_key).unwrap();
+        assert_eq!(wallet.check_trust_layer_refunds(vec![]), Ok(vec![]));
+
+        let color_id =
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string();
+        let address = "1111111111111111111114oLvT2".to_string();
+        let colored = add_color_to_address(address.clone(), color_id.clone()).unwrap();
         assert_eq!(
-            address,
-            Address::p2pkh(&public_key, wallet.network).to_string()
+            wallet
+                .check_trust_layer_tpc_refund_report("invalid".to_string(), Some(colored.clone())),
+            Err(CheckTrustLayerRefundError::InvalidRefundAddress {
+                address: colored.clone()
+            })
+        );
+        assert_eq!(
+            wallet.check_trust_layer_tpc_refund_report(
+                "invalid".to_string(),
+                Some("mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string())
+            ),
+            Err(CheckTrustLayerRefundError::WrongNetworkAddress {
+                address: "mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string()
+            })
         );
 
-        let color_id = ColorIdentifier::from_str(
-            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46",
-        )
-        .unwrap();
-        let GetNewAddressResult {
-            address,
-            public_key,
-        } = wallet.get_new_address(Some(color_id.to_string())).unwrap();
-        assert_eq!(address.len(), 78, "Address should be 78 characters long");
-        let public_key = PublicKey::from_str(&public_key).unwrap();
-        let spk = ScriptBuf::new_cp2pkh(&color_id, &PubkeyHash::from(public_key));
-        let expected = Address::from_script(&spk, wallet.network)
-            .unwrap()
-            .to_string();
-        assert_eq!(address, expected);
+        // The invalid request does not fail the other requests
+        let results = wallet
+            .check_trust_layer_refunds(vec![
+                TrustLayerRefundRequest {
+                    txid: "invalid".to_string(),
+                    color_id: Some("invalid".to_string()),
+                    refund_address: None,
+                },
+                TrustLayerRefundRequest {
+                    txid: "invalid".to_string(),
+                    color_id: None,
+                    refund_address: None,
+                },
+                TrustLayerRefundRequest {
+                    txid: "invalid".to_string(),
+                    color_id: Some(color_id.clone()),
+                    refund_address: Some(colored),
+                },
+                TrustLayerRefundRequest {
+                    txid: "invalid".to_string(),
+                    color_id: None,
+                    refund_address: Some(address),
+                },
+            ])
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                TrustLayerRefundResult {
+                    txid: "invalid".to_string(),
+                    color_id: Some("invalid".to_string()),
+                    report: None,
+                    error: Some("Invalid color id".to_string()),
+                },
+                TrustLayerRefundResult {
+                    txid: "invalid".to_string(),
+                    color_id: None,
+                    report: None,
+                    error: Some("Failed to parse txid: invalid".to_string()),
+                },
+                TrustLayerRefundResult {
+                    txid: "invalid".to_string(),
+                    color_id: Some(color_id),
+                    report: None,
+                    error: Some("Failed to parse txid: invalid".to_string()),
+                },
+                TrustLayerRefundResult {
+                    txid: "invalid".to_string(),
+                    color_id: None,
+                    report: None,
+                    error: Some("Failed to parse txid: invalid".to_string()),
+                }
+            ]
+        );
     }
 
     #[test]
@@ -1638,59 +5648,700 @@ mod test {
             master_key_path: None,
             master_key: Some(master_key),
             db_file_path: Some(db_file_path),
+            esplora_headers: None,
+            esplora_token_provider: None,
         };
         HdWallet::new(Arc::new(config)).unwrap();
     }
 
+    struct StaticTokenProvider(String);
+
+    impl EsploraTokenProvider for StaticTokenProvider {
+        fn token(&self) -> String {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn test_esplora_headers() {
+        let user = Some("user".to_string());
+        let password = Some("password".to_string());
+        let headers = HashMap::from([("X-API-KEY".to_string(), "api-key".to_string())]);
+
+        let result = HdWallet::esplora_headers(&user, &password, &headers, &None);
+        assert_eq!(
+            result.get("Authorization"),
+            Some(&"Basic dXNlcjpwYXNzd29yZA==".to_string())
+        );
+        assert_eq!(result.get("X-API-KEY"), Some(&"api-key".to_string()));
+
+        let token_provider: Option<Arc<dyn EsploraTokenProvider>> =
+            Some(Arc::new(StaticTokenProvider("token".to_string())));
+        let result = HdWallet::esplora_headers(&user, &password, &headers, &token_provider);
+        assert_eq!(
+            result.get("Authorization"),
+            Some(&"Bearer token".to_string())
+        );
+        assert_eq!(result.get("X-API-KEY"), Some(&"api-key".to_string()));
+        assert_eq!(result.len(), 2);
+    }
+
+    #[test]
+    fn test_balance() {
+        let wallet = get_wallet();
+        let balance = wallet.balance(None).unwrap();
+        assert_eq!(balance, 0, "Balance should be 0");
+
+        let color_id = ColorIdentifier::from_str(
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46",
+        )
+        .unwrap();
+        let balance = wallet.balance(Some(color_id.to_string())).unwrap();
+        assert_eq!(balance, 0, "Balance should be 0");
+    }
+
+    #[test]
+    fn test_wallet_snapshot_events() {
+        let txid1 = MalFixTxid::from_str(
+            "1111111111111111111111111111111111111111111111111111111111111111",
+        )
+        .unwrap();
+        let txid2 = MalFixTxid::from_str(
+            "2222222222222222222222222222222222222222222222222222222222222222",
+        )
+        .unwrap();
+        let txid3 = MalFixTxid::from_str(
+            "3333333333333333333333333333333333333333333333333333333333333333",
+        )
+        .unwrap();
+        let color_id =
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string();
+
+        let before = WalletSnapshot {
+            txs: BTreeMap::from([(txid1, None), (txid2, None)]),
+            balances: BTreeMap::from([(None, 1000)]),
+            ..Default::default()
+        };
+        let after = WalletSnapshot {
+            txs: BTreeMap::from([(txid1, Some(10)), (txid3, None)]),
+            balances: BTreeMap::from([(None, 1000), (Some(color_id.clone()), 100)]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            before.events(&after),
+            vec![
+                WalletEvent::TransactionConfirmed {
+                    txid: txid1.to_string(),
+                    height: 10
+                },
+                WalletEvent::TransactionReceived {
+                    txid: txid3.to_string()
+                },
+                WalletEvent::TransactionEvicted {
+                    txid: txid2.to_string()
+                },
+                WalletEvent::BalanceChanged {
+                    color_id: Some(color_id),
+                    old_balance: 0,
+                    new_balance: 100
+                },
+            ]
+        );
+        assert!(after.events(&after).is_empty());
+    }
+
+    #[test]
+    fn test_wallet_snapshot_reorg() {
+        let block_hash = |n: u8| BlockHash::from_str(&format!("{:02x}", n).repeat(32)).unwrap();
+        let txid = |n: u8| MalFixTxid::from_str(&format!("{:02x}", n).repeat(32)).unwrap();
+
+        let before = WalletSnapshot {
+            blocks: BTreeMap::from([
+                (0, block_hash(0)),
+                (9, block_hash(9)),
+                (10, block_hash(10)),
+                (11, block_hash(11)),
+            ]),
+            txs: BTreeMap::from([(txid(1), Some(9)), (txid(2), Some(10)), (txid(3), Some(11))]),
+            ..Default::default()
+        };
+        assert_eq!(before.reorg(&before), None);
+
+        // The blocks are extended without reorg
+        let mut extended = before.blocks.clone();
+        extended.insert(12, block_hash(12));
+        let after = WalletSnapshot {
+            blocks: extended,
+            txs: before.txs.clone(),
+            ..Default::default()
+        };
+        assert_eq!(before.reorg(&after), None);
+
+        // The block 10 and 11 are replaced, and tx 3 is not confirmed yet in the new chain
+        let after = WalletSnapshot {
+            blocks: BTreeMap::from([
+                (0, block_hash(0)),
+                (9, block_hash(9)),
+                (10, block_hash(100)),
+                (11, block_hash(110)),
+                (12, block_hash(120)),
+            ]),
+            txs: BTreeMap::from([(txid(1), Some(9)), (txid(2), Some(10)), (txid(3), None)]),
+            ..Default::default()
+        };
+        let reorg = ReorgInfo {
+            fork_height: 9,
+            affected_txids: vec![txid(2).to_string(), txid(3).to_string()],
+        };
+        assert_eq!(before.reorg(&after), Some(reorg.clone()));
+        assert_eq!(
+            before.events(&after).first(),
+            Some(&WalletEvent::Reorg { reorg })
+        );
+    }
+
+    #[test]
+    fn test_sync_status() {
+        let wallet = get_wallet();
+        let status = wallet.sync_status().unwrap();
+        assert_eq!(status.local_tip.height, 0);
+        assert_eq!(
+            status.local_tip.block_hash,
+            "038b114875c2f78f5a2fd7d8549a905f38ea5faee6e29a3d79e547151d6bdd8a"
+        );
+        assert_eq!(status.last_sync_time, None);
+        assert!(!status.full_scan_completed);
+    }
+
+    #[test]
+    fn test_sync_addresses_error() {
+        let wallet = get_wallet();
+        assert!(matches!(
+            wallet.sync_addresses(vec!["invalid".to_string()]),
+            Err(SyncAddressesError::FailedToParseAddress { address }) if address == "invalid"
+        ));
+
+        // Address for Dev network
+        let address = "mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string();
+        assert!(matches!(
+            wallet.sync_addresses(vec![address.clone()]),
+            Err(SyncAddressesError::WrongNetworkAddress { address: e }) if e == address
+        ));
+
+        // Address which is not of the wallet
+        let address = "1111111111111111111114oLvT2".to_string();
+        assert!(matches!(
+            wallet.sync_addresses(vec![address.clone()]),
+            Err(SyncAddressesError::UnknownAddress { address: e }) if e == address
+        ));
+    }
+
+    #[test]
+    fn test_calc_p2c_address() {
+        let wallet = get_wallet();
+        let public_key =
+            "039be0d2b0c3b6f7fad77f142257aee12b2a34047aa3191edc0424cd15e0fa15da".to_string();
+        let address = wallet
+            .calc_p2c_address(public_key, "content".to_string(), None, None)
+            .expect("Failed to calculate P2C address");
+        assert_eq!(
+            address, "1NUKT87AxtsJ74EiZ6esDz8kjppHS4cKz2",
+            "Address should be equal"
+        );
+    }
+
+    #[test]
+    fn test_store_contract() {
+        let wallet = get_wallet();
+        let GetNewAddressResult {
+            address,
+            public_key,
+        } = wallet.get_new_address(None).unwrap();
+        let contract = Contract {
+            contract_id: "contract_id".to_string(),
+            contract: "contract".to_string(),
+            payment_base: public_key,
+            payable: true,
+            encoding: None,
+        };
+        let stored_contract = wallet
+            .store_contract(contract.clone())
+            .expect("Failed to store contract");
+
+        // Update contract
+        let updated_contract = wallet
+            .update_contract(contract.contract_id.clone(), false)
+            .expect("Failed to update contract");
+    }
+
+    #[test]
+    fn test_verify_p2c_address() {
+        let wallet = get_wallet();
+        let GetNewAddressResult { public_key, .. } = wallet.get_new_address(None).unwrap();
+        let color_id =
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string();
+
+        let address = wallet
+            .calc_p2c_address(public_key.clone(), "contract".to_string(), None, None)
+            .unwrap();
+        let result = wallet
+            .verify_p2c_address(
+                address.clone(),
+                public_key.clone(),
+                "contract".to_string(),
+                None,
+            )
+            .unwrap();
+        assert!(result.matches);
+        assert_eq!(result.expected_address, address);
+
+        // The public key of the address is payment_base + commitment * G
+        let commitment: [u8; 32] = Vec::from_hex(&result.commitment)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let tweak = secp256k1::Scalar::from_be_bytes(commitment).unwrap();
+        let payment_base = PublicKey::from_str(&public_key).unwrap();
+        let p2c_key = payment_base
+            .inner
+            .add_exp_tweak(&secp256k1::Secp256k1::verification_only(), &tweak)
+            .unwrap();
+        assert_eq!(
+            Address::p2pkh(&PublicKey::new(p2c_key), wallet.network).to_string(),
+            address
+        );
+
+        let colored_address = wallet
+            .calc_p2c_address(
+                public_key.clone(),
+                "contract".to_string(),
+                Some(color_id),
+                None,
+            )
+            .unwrap();
+        let colored = wallet
+            .verify_p2c_address(
+                colored_address,
+                public_key.clone(),
+                "contract".to_string(),
+                None,
+            )
+            .unwrap();
+        assert!(colored.matches);
+        assert_eq!(colored.commitment, result.commitment);
+
+        let other = wallet
+            .verify_p2c_address(address, public_key, "other contract".to_string(), None)
+            .unwrap();
+        assert!(!other.matches);
+        assert_ne!(other.commitment, result.commitment);
+    }
+
+    #[test]
+    fn test_contract_encoding() {
+        let wallet = get_wallet();
+        let GetNewAddressResult { public_key, .. } = wallet.get_new_address(None).unwrap();
+
+        // The contract which is not UTF-8, such as the hash of a PDF
+        let binary = "ff00fe01".to_string();
+        let stored = wallet
+            .store_contract(Contract {
+                contract_id: "binary".to_string(),
+                contract: binary.clone(),
+                payment_base: public_key.clone(),
+                payable: true,
+                encoding: Some(ContractEncoding::Hex),
+            })
+            .unwrap();
+        assert_eq!(stored.contract, binary);
+        assert_eq!(stored.encoding, Some(ContractEncoding::Hex));
+        let base64 = wallet
+            .calc_p2c_address(
+                public_key.clone(),
+                "/wD+AQ==".to_string(),
+                None,
+                Some(ContractEncoding::Base64),
+            )
+            .unwrap();
+        let hex = wallet
+            .calc_p2c_address(
+                public_key.clone(),
+                binary,
+                None,
+                Some(ContractEncoding::Hex),
+            )
+            .unwrap();
+        assert_eq!(base64, hex);
+
+        // The JSON document is committed in the canonical form
+        let stored = wallet
+            .store_contract(Contract {
+                contract_id: "json".to_string(),
+                contract: "{ \"b\": 1, \"a\": [true, null] }".to_string(),
+                payment_base: public_key.clone(),
+                payable: true,
+                encoding: Some(ContractEncoding::Json),
+            })
+            .unwrap();
+        assert_eq!(stored.contract, "{\"a\":[true,null],\"b\":1}");
+        let json = wallet
+            .calc_p2c_address(
+                public_key.clone(),
+                "{\"a\":[true,null],\n\"b\":1}".to_string(),
+                None,
+                Some(ContractEncoding::Json),
+            )
+            .unwrap();
+        let utf8 = wallet
+            .calc_p2c_address(public_key.clone(), stored.contract, None, None)
+            .unwrap();
+        assert_eq!(json, utf8);
+
+        assert!(matches!(
+            wallet.calc_p2c_address(
+                public_key,
+                "not hex".to_string(),
+                None,
+                Some(ContractEncoding::Hex)
+            ),
+            Err(CalcPayToContractAddressError::ContractError { .. })
+        ));
+        assert_eq!(
+            ContractEncoding::Utf8.encode(&[0xff, 0x00]),
+            ("ff00".to_string(), ContractEncoding::Hex)
+        );
+    }
+
+    #[test]
+    fn test_export_and_import_contracts() {
+        let wallet = get_wallet();
+        let GetNewAddressResult { public_key, .. } = wallet.get_new_address(None).unwrap();
+        wallet
+            .store_contract(Contract {
+                contract_id: "binary".to_string(),
+                contract: "ff00".to_string(),
+                payment_base: public_key.clone(),
+                payable: false,
+                encoding: Some(ContractEncoding::Hex),
+            })
+            .unwrap();
+        let bundle = wallet.export_contracts().unwrap();
+
+        // The wallet restored from the same master key
+        let restored = get_wallet();
+        let tampered = bundle.replace("\"payable\":false", "\"payable\":true");
+        assert_ne!(tampered, bundle);
+        assert!(matches!(
+            restored.import_contracts(tampered),
+            Err(ImportContractsError::InvalidHmac)
+        ));
+        let unsupported = bundle.replace("\"version\":1", "\"version\":2");
+        assert!(matches!(
+            restored.import_contracts(unsupported),
+            Err(ImportContractsError::UnsupportedVersion { version: 2 })
+        ));
+        assert!(matches!(
+            restored.import_contracts("{}".to_string()),
+            Err(ImportContractsError::InvalidBundle { .. })
+        ));
+
+        let imported = restored.import_contracts(bundle.clone()).unwrap();
+        assert_eq!(imported.len(), 1);
+        let contract = restored.get_contract("binary".to_string()).unwrap();
+        assert_eq!(contract.contract, "ff00");
+        assert_eq!(contract.encoding, Some(ContractEncoding::Hex));
+        assert_eq!(contract.payment_base, public_key);
+        assert!(!contract.payable);
+
+        // The contracts already stored are skipped
+        assert!(restored
+            .import_contracts(bundle.clone())
+            .unwrap()
+            .is_empty());
+        assert_eq!(restored.list_contracts().unwrap().len(), 1);
+
+        // Nothing is imported if one of the contracts conflicts with the stored contract
+        wallet
+            .store_contract(Contract {
+                contract_id: "text".to_string(),
+                contract: "text".to_string(),
+                payment_base: public_key.clone(),
+                payable: true,
+                encoding: None,
+            })
+            .unwrap();
+        let bundle = wallet.export_contracts().unwrap();
+        let conflicted = get_wallet();
+        conflicted
+            .store_contract(Contract {
+                contract_id: "binary".to_string(),
+                contract: "0000".to_string(),
+                payment_base: public_key,
+                payable: true,
+                encoding: Some(ContractEncoding::Hex),
+            })
+            .unwrap();
+        assert!(matches!(
+            conflicted.import_contracts(bundle.clone()),
+            Err(ImportContractsError::ContractError { .. })
+        ));
+        assert_eq!(
+            conflicted.get_contract("text".to_string()).unwrap_err(),
+            GetContractError::UnknownContract
+        );
+
+        // The wallet with the other master key can not import the bundle
+        let other = get_wallet_with_master_key(generate_master_key(Network::Prod));
+        assert!(matches!(
+            other.import_contracts(bundle),
+            Err(ImportContractsError::InvalidHmac)
+        ));
+    }
+
+    struct StaticContractRegistry {
+        contracts: Vec<Contract>,
+        requested: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ContractRegistry for StaticContractRegistry {
+        fn find_contracts(&self, payment_bases: Vec<String>) -> Vec<Contract> {
+            *self.requested.lock().unwrap() = payment_bases;
+            self.contracts.clone()
+        }
+    }
+
+    #[test]
+    fn test_discover_contracts() {
+        let wallet = get_wallet();
+        assert!(wallet.find_contract_candidates().1.is_empty());
+
+        let GetNewAddressResult { public_key, .. } = wallet.get_new_address(None).unwrap();
+        let other_key = Xpriv::from_str(&generate_master_key(Network::Prod)).unwrap();
+        let other_public_key = other_key
+            .to_priv()
+            .public_key(&secp256k1::Secp256k1::new())
+            .to_string();
+        let contract = |id: &str, payment_base: &String, content: &str| Contract {
+            contract_id: id.to_string(),
+            contract: content.to_string(),
+            payment_base: payment_base.clone(),
+            payable: true,
+            encoding: Some(ContractEncoding::Hex),
+        };
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        wallet.set_contract_registry(Some(Box::new(StaticContractRegistry {
+            contracts: vec![
+                contract("other", &other_public_key, "ff00"),
+                contract("invalid", &public_key, "not hex"),
+            ],
+            requested: requested.clone(),
+        })));
+
+        // The contracts for the other wallet and the invalid contracts are skipped
+        let (payment_bases, candidates) = wallet.find_contract_candidates();
+        assert_eq!(candidates.len(), 2);
+        assert!(wallet
+            .discover_contracts(&payment_bases, candidates)
+            .is_empty());
+        assert_eq!(*requested.lock().unwrap(), vec![public_key]);
+        assert!(wallet.list_contracts().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_list_get_and_delete_contract() {
+        let wallet = get_wallet();
+        let GetNewAddressResult { public_key, .. } = wallet.get_new_address(None).unwrap();
+        for id in ["contract_b", "contract_a"] {
+            wallet
+                .store_contract(Contract {
+                    contract_id: id.to_string(),
+                    contract: format!("content of {}", id),
+                    payment_base: public_key.clone(),
+                    payable: true,
+                    encoding: None,
+                })
+                .expect("Failed to store contract");
+        }
+        wallet
+            .update_contract("contract_b".to_string(), false)
+            .expect("Failed to update contract");
+
+        let contracts = wallet.list_contracts().unwrap();
+        let ids: Vec<_> = contracts.iter().map(|c| c.contract_id.as_str()).collect();
+        assert_eq!(ids, vec!["contract_a", "contract_b"]);
+
+        let contract = wallet.get_contract("contract_b".to_string()).unwrap();
+        assert_eq!(contract.contract, "content of contract_b");
+        assert_eq!(contract.payment_base, public_key);
+        assert!(!contract.payable);
+        assert_eq!(
+            wallet.get_contract("unknown".to_string()).unwrap_err(),
+            GetContractError::UnknownContract
+        );
+
+        wallet
+            .delete_contract("contract_a".to_string())
+            .expect("Failed to delete contract");
+        assert_eq!(wallet.list_contracts().unwrap().len(), 1);
+        assert_eq!(
+            wallet.get_contract("contract_a".to_string()).unwrap_err(),
+            GetContractError::UnknownContract
+        );
+        // Deleting the deleted contract again succeeds
+        assert_eq!(wallet.delete_contract("contract_a".to_string()), Ok(()));
+        assert_eq!(
+            wallet.delete_contract("unknown".to_string()),
+            Err(DeleteContractError::UnknownContract)
+        );
+
+        // The deleted contract can be stored again with the same content
+        let contract = Contract {
+            contract_id: "contract_a".to_string(),
+            contract: "content of contract_a".to_string(),
+            payment_base: public_key.clone(),
+            payable: true,
+            encoding: None,
+        };
+        assert!(matches!(
+            wallet.store_contract(Contract {
+                contract: "other content".to_string(),
+                ..contract.clone()
+            }),
+            Err(StoreContractError::ContractError { .. })
+        ));
+        assert_eq!(
+            wallet.get_contract("contract_a".to_string()).unwrap_err(),
+            GetContractError::UnknownContract
+        );
+        wallet
+            .store_contract(contract)
+            .expect("Failed to store contract");
+        let contract = wallet.get_contract("contract_a".to_string()).unwrap();
+        assert_eq!(contract.contract, "content of contract_a");
+        assert!(contract.payable);
+        assert_eq!(wallet.list_contracts().unwrap().len(), 2);
+    }
+
     #[test]
-    fn test_balance() {
+    fn test_contract_funds() {
         let wallet = get_wallet();
-        let balance = wallet.balance(None).unwrap();
-        assert_eq!(balance, 0, "Balance should be 0");
-
-        let color_id = ColorIdentifier::from_str(
-            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46",
-        )
-        .unwrap();
-        let balance = wallet.balance(Some(color_id.to_string())).unwrap();
-        assert_eq!(balance, 0, "Balance should be 0");
-    }
+        let GetNewAddressResult { public_key, .. } = wallet.get_new_address(None).unwrap();
+        wallet
+            .store_contract(Contract {
+                contract_id: "contract_id".to_string(),
+                contract: "contract".to_string(),
+                payment_base: public_key,
+                payable: true,
+                encoding: None,
+            })
+            .expect("Failed to store contract");
 
-    #[test]
-    fn test_calc_p2c_address() {
-        let wallet = get_wallet();
-        let public_key =
-            "039be0d2b0c3b6f7fad77f142257aee12b2a34047aa3191edc0424cd15e0fa15da".to_string();
-        let address = wallet
-            .calc_p2c_address(public_key, "content".to_string(), None)
-            .expect("Failed to calculate P2C address");
-        assert_eq!(
-            address, "1NUKT87AxtsJ74EiZ6esDz8kjppHS4cKz2",
-            "Address should be equal"
-        );
+        assert!(wallet
+            .contract_balance("contract_id".to_string())
+            .unwrap()
+            .is_empty());
+        assert!(wallet
+            .list_contract_utxos("contract_id".to_string())
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            wallet.list_contract_utxos("unknown".to_string()),
+            Err(ContractFundsError::UnknownContract)
+        ));
+
+        // The contract which has the same pay to contract address is rejected
+        assert!(matches!(
+            wallet.store_contract(Contract {
+                contract_id: "other_contract_id".to_string(),
+                contract: "contract".to_string(),
+                payment_base: public_key,
+                payable: true,
+                encoding: None,
+            }),
+            Err(StoreContractError::ContractError { .. })
+        ));
+        assert_eq!(wallet.list_contracts().unwrap().len(), 1);
     }
 
     #[test]
-    fn test_store_contract() {
+    fn test_refund_trust_layer_transfer_error() {
         let wallet = get_wallet();
-        let GetNewAddressResult {
-            address,
-            public_key,
-        } = wallet.get_new_address(None).unwrap();
-        let contract = Contract {
-            contract_id: "contract_id".to_string(),
-            contract: "contract".to_string(),
-            payment_base: public_key,
-            payable: true,
+        let GetNewAddressResult { public_key, .. } = wallet.get_new_address(None).unwrap();
+        for (id, payable) in [("payable", true), ("not_payable", false)] {
+            wallet
+                .store_contract(Contract {
+                    contract_id: id.to_string(),
+                    contract: format!("content of {}", id),
+                    payment_base: public_key.clone(),
+                    payable,
+                    encoding: None,
+                })
+                .expect("Failed to store contract");
+        }
+        let not_payable_address = wallet
+            .calc_p2c_address(
+                public_key.clone(),
+                "content of not_payable".to_string(),
+                None,
+                None,
+            )
+            .unwrap();
+        let unknown_address = wallet
+            .calc_p2c_address(public_key, "unknown".to_string(), None, None)
+            .unwrap();
+        let txid = "0000000000000000000000000000000000000000000000000000000000000000".to_string();
+        let refund_address = "1111111111111111111114oLvT2".to_string();
+        let refund = |txid: &str, contract_id: Option<&str>, p2c_address: Option<&String>| {
+            wallet.refund_trust_layer_transfer(
+                txid.to_string(),
+                refund_address.clone(),
+                contract_id.map(|id| id.to_string()),
+                p2c_address.cloned(),
+            )
         };
-        let stored_contract = wallet
-            .store_contract(contract.clone())
-            .expect("Failed to store contract");
 
-        // Update contract
-        let updated_contract = wallet
-            .update_contract(contract.contract_id.clone(), false)
-            .expect("Failed to update contract");
+        assert_eq!(
+            refund("invalid", Some("payable"), None),
+            Err(RefundTrustLayerTransferError::FailedToParseTxid {
+                txid: "invalid".to_string()
+            })
+        );
+        assert_eq!(
+            wallet.refund_trust_layer_transfer(
+                txid.clone(),
+                "mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string(),
+                Some("payable".to_string()),
+                None
+            ),
+            Err(RefundTrustLayerTransferError::WrongNetworkAddress {
+                address: "mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string()
+            })
+        );
+        assert_eq!(
+            refund(&txid, None, None),
+            Err(RefundTrustLayerTransferError::ContractNotSpecified)
+        );
+        assert_eq!(
+            refund(&txid, Some("unknown"), None),
+            Err(RefundTrustLayerTransferError::UnknownContract)
+        );
+        assert_eq!(
+            refund(&txid, None, Some(&unknown_address)),
+            Err(RefundTrustLayerTransferError::UnknownContract)
+        );
+        assert_eq!(
+            refund(&txid, Some("payable"), Some(&not_payable_address)),
+            Err(RefundTrustLayerTransferError::ContractMismatch)
+        );
+        assert_eq!(
+            refund(&txid, None, Some(&not_payable_address)),
+            Err(RefundTrustLayerTransferError::ContractNotPayable)
+        );
+        assert_eq!(
+            refund(&txid, Some("payable"), None),
+            Err(RefundTrustLayerTransferError::NoRefundableOutput)
+        );
     }
 
     #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
@@ -1790,6 +6441,7 @@ mod test {
                 public_key.clone(),
                 "content".to_string(),
                 Some(color_id.clone().to_string()),
+                None,
             )
             .unwrap();
 
@@ -1808,6 +6460,7 @@ mod test {
             contract: "content".to_string(),
             payment_base: public_key,
             payable: false,
+            encoding: None,
         };
 
         wallet
@@ -1865,6 +6518,7 @@ mod test {
                 receiver_public_key.clone(),
                 contract.to_string(),
                 Some(color_id.clone().to_string()),
+                None,
             )
             .unwrap();
         let transfer_txid = sender_wallet
@@ -1892,6 +6546,7 @@ mod test {
             contract: contract.to_string(),
             payment_base: receiver_public_key.clone(),
             payable: false,
+            encoding: None,
         };
         receiver_wallet
             .store_contract(contract.clone())
@@ -1913,10 +6568,15 @@ mod test {
         // Sender check refund but it will not have any refund
         assert_eq!(
             sender_wallet
-                .check_trust_layer_refund(transfer_txid.clone(), color_id.clone().to_string())
+                .check_trust_layer_refund(transfer_txid.clone(), color_id.to_string())
                 .unwrap(),
             0
         );
+        let report = sender_wallet
+            .check_trust_layer_refund_report(transfer_txid.clone(), color_id.to_string())
+            .unwrap();
+        assert_eq!(report.unresolved_outputs, vec![report.outputs[0].index]);
+        assert_eq!(report.outputs[0].status, RefundStatus::Unspent);
 
         // Receiver refund the token
         let GetNewAddressResult {
@@ -1929,7 +6589,7 @@ mod test {
             .get_transaction(transfer_txid.clone())
             .unwrap();
         let utxo = receiver_wallet
-            .get_tx_out_by_address(tx, p2c_address)
+            .get_tx_out_by_address(tx, p2c_address.clone())
             .unwrap();
         let refund_txid = receiver_wallet
             .transfer(
@@ -1960,25 +6620,334 @@ mod test {
         // Sender check refund and it will have refund
         println!("check_trust_layer_refund");
         assert_eq!(
-            sender_wallet
-                .check_trust_layer_refund(transfer_txid.clone(), color_id.clone().to_string())
+            sender_wallet
+                .check_trust_layer_refund(transfer_txid.clone(), color_id.to_string())
+                .unwrap(),
+            10
+        );
+
+        let report = sender_wallet
+            .check_trust_layer_refund_report(transfer_txid.clone(), color_id.to_string())
+            .unwrap();
+        assert_eq!(report.total_refund, 10);
+        assert!(report.unresolved_outputs.is_empty());
+        assert_eq!(report.outputs.len(), 1);
+        let output = &report.outputs[0];
+        assert_eq!(output.amount, 10);
+        assert_eq!(output.address, p2c_address);
+        assert_eq!(output.status, RefundStatus::Refunded);
+        assert_eq!(output.refund_txid, Some(refund_txid.clone()));
+        assert_eq!(output.spending_txid, Some(refund_txid));
+        assert_eq!(output.refund_amount, 10);
+
+        let results = sender_wallet
+            .check_trust_layer_refunds(vec![TrustLayerRefundRequest {
+                txid: transfer_txid.clone(),
+                color_id: Some(color_id.to_string()),
+                refund_address: None,
+            }])
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].report, Some(report));
+        assert_eq!(results[0].error, None);
+    }
+
+    #[test]
+    fn test_refund() {
+        let (env, color_id, client) = prepare_token();
+        let sender_wallet = get_wallet_testenv(&env, &client, None);
+        distribute_token(&sender_wallet, &env, &color_id, &client);
+
+        let receiver_wallet_config = get_wallet_config_testenv(&env, Some("tprv8ZgxMBicQKsPfKH3fHRJGBs9Vt2hMHfroZuZ5yYLYZgwvC3Hc8Wksn1HDinon77ZvDNEo25BEefQ6Ldgi4Nw29o1gP7pY8QzAyn1WQimrdc".to_string()));
+        let receiver_wallet = get_wallet_by_config(receiver_wallet_config.clone(), &env, &client);
+
+        distribute_token(&receiver_wallet, &env, &color_id, &client);
+
+        // Receiver generate new public key and notify to the sender
+        let GetNewAddressResult {
+            public_key: receiver_public_key,
+            ..
+        } = receiver_wallet
+            .get_new_address(Some(color_id.to_string()))
+            .unwrap();
+
+        transfer_and_refund(
+            &sender_wallet,
+            &receiver_wallet,
+            &receiver_public_key,
+            &env,
+            &color_id,
+            &client,
+            "contract_id",
+            "contract",
+        );
+
+        // Refund multiple times from one wallet.
+        transfer_and_refund(
+            &sender_wallet,
+            &receiver_wallet,
+            &receiver_public_key,
+            &env,
+            &color_id,
+            &client,
+            "contract_id2",
+            "contract",
+        );
+    }
+
+    #[test]
+    fn test_refund_tpc() {
+        let (env, _color_id, client) = prepare_token();
+        let sender_wallet = get_wallet_testenv(&env, &client, None);
+
+        let receiver_wallet_config = get_wallet_config_testenv(&env, Some("tprv8ZgxMBicQKsPfKH3fHRJGBs9Vt2hMHfroZuZ5yYLYZgwvC3Hc8Wksn1HDinon77ZvDNEo25BEefQ6Ldgi4Nw29o1gP7pY8QzAyn1WQimrdc".to_string()));
+        let receiver_wallet = get_wallet_by_config(receiver_wallet_config, &env, &client);
+
+        let GetNewAddressResult {
+            public_key: receiver_public_key,
+            ..
+        } = receiver_wallet.get_new_address(None).unwrap();
+
+        // Sender transfers TPC to the P2C address
+        let p2c_address = sender_wallet
+            .calc_p2c_address(
+                receiver_public_key.clone(),
+                "tpc contract".to_string(),
+                None,
+                None,
+            )
+            .unwrap();
+        let transfer_txid = sender_wallet
+            .transfer(
+                vec![TransferParams {
+                    amount: 5000,
+                    to_address: p2c_address.clone(),
+                }],
+                vec![],
+            )
+            .expect("Failed to transfer");
+        wait_for_confirmation(&env, &client, 1);
+        sender_wallet.sync().expect("Failed to sync");
+
+        // The refund address narrows the refund outputs
+        let GetNewAddressResult {
+            address: refund_address,
+            ..
+        } = sender_wallet.get_new_address(None).unwrap();
+        let GetNewAddressResult {
+            address: other_address,
+            ..
+        } = sender_wallet.get_new_address(None).unwrap();
+        let report = sender_wallet
+            .check_trust_layer_tpc_refund_report(
+                transfer_txid.clone(),
+                Some(refund_address.clone()),
+            )
+            .unwrap();
+        assert_eq!(report.color_id, None);
+        assert_eq!(report.total_refund, 0);
+        assert_eq!(report.outputs.len(), 1);
+        assert_eq!(report.outputs[0].amount, 5000);
+        assert_eq!(report.outputs[0].address, p2c_address);
+        assert_eq!(report.unresolved_outputs, vec![report.outputs[0].index]);
+
+        // Receiver refunds a part of TPC
+        receiver_wallet
+            .store_contract(Contract {
+                contract_id: "tpc_contract_id".to_string(),
+                contract: "tpc contract".to_string(),
+                payment_base: receiver_public_key,
+                payable: false,
+                encoding: None,
+            })
+            .unwrap();
+        receiver_wallet.sync().expect("Failed to sync");
+        let tx = receiver_wallet
+            .get_transaction(transfer_txid.clone())
+            .unwrap();
+        let utxo = receiver_wallet
+            .get_tx_out_by_address(tx, p2c_address)
+            .unwrap();
+        let refund_txid = receiver_wallet
+            .transfer(
+                vec![TransferParams {
+                    amount: 4000,
+                    to_address: refund_address,
+                }],
+                utxo,
+            )
+            .expect("Failed to refund");
+        wait_for_confirmation(&env, &client, 1);
+        sender_wallet.sync().expect("Failed to sync");
+
+        let report = sender_wallet
+            .check_trust_layer_tpc_refund_report(transfer_txid.clone(), Some(refund_address))
+            .unwrap();
+        assert_eq!(report.total_refund, 4000);
+        assert!(report.unresolved_outputs.is_empty());
+        assert_eq!(report.outputs[0].status, RefundStatus::Refunded);
+        assert_eq!(report.outputs[0].refund_txid, Some(refund_txid.clone()));
+        assert_eq!(report.outputs[0].refund_amount, 4000);
+
+        // Without the refund address, the uncolored outputs paid to the wallet are the refund
+        let report = sender_wallet
+            .check_trust_layer_tpc_refund_report(transfer_txid.clone(), None)
+            .unwrap();
+        assert_eq!(report.total_refund, 4000);
+        assert_eq!(report.outputs[0].status, RefundStatus::Refunded);
+
+        // The output paid to the other address is not the refund
+        let report = sender_wallet
+            .check_trust_layer_tpc_refund_report(transfer_txid, Some(other_address))
+            .unwrap();
+        assert_eq!(report.total_refund, 0);
+        assert_eq!(report.outputs[0].status, RefundStatus::Spent);
+        assert_eq!(report.outputs[0].spending_txid, Some(refund_txid));
+    }
+
+    #[test]
+    fn test_contract_funds_with_payment() {
+        let (env, color_id, client) = prepare_token();
+        let sender_wallet = get_wallet_testenv(&env, &client, None);
+        distribute_token(&sender_wallet, &env, &color_id, &client);
+
+        let receiver_wallet_config = get_wallet_config_testenv(&env, Some("tprv8ZgxMBicQKsPfKH3fHRJGBs9Vt2hMHfroZuZ5yYLYZgwvC3Hc8Wksn1HDinon77ZvDNEo25BEefQ6Ldgi4Nw29o1gP7pY8QzAyn1WQimrdc".to_string()));
+        let receiver_wallet = get_wallet_by_config(receiver_wallet_config, &env, &client);
+        let GetNewAddressResult {
+            public_key: receiver_public_key,
+            ..
+        } = receiver_wallet.get_new_address(None).unwrap();
+        for (contract_id, contract) in [("contract_a", "contract a"), ("contract_b", "contract b")]
+        {
+            receiver_wallet
+                .store_contract(Contract {
+                    contract_id: contract_id.to_string(),
+                    contract: contract.to_string(),
+                    payment_base: receiver_public_key.clone(),
+                    payable: true,
+                    encoding: None,
+                })
+                .unwrap();
+        }
+
+        let p2c_address = sender_wallet
+            .calc_p2c_address(
+                receiver_public_key.clone(),
+                "contract a".to_string(),
+                Some(color_id.to_string()),
+                None,
+            )
+            .unwrap();
+        let txid = sender_wallet
+            .transfer(
+                vec![TransferParams {
+                    amount: 10,
+                    to_address: p2c_address.clone(),
+                }],
+                vec![],
+            )
+            .expect("Failed to transfer");
+        wait_for_confirmation(&env, &client, 1);
+        receiver_wallet.sync().expect("Failed to sync");
+
+        assert_eq!(
+            receiver_wallet
+                .contract_balance("contract_a".to_string())
+                .unwrap(),
+            vec![ColoredAmount {
+                color_id: Some(color_id.to_string()),
+                amount: 10,
+            }]
+        );
+        let utxos = receiver_wallet
+            .list_contract_utxos("contract_a".to_string())
+            .unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].txid, txid);
+        assert_eq!(utxos[0].address, p2c_address);
+        assert!(receiver_wallet
+            .contract_balance("contract_b".to_string())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_discover_paid_contracts() {
+        let (env, color_id, client) = prepare_token();
+        let sender_wallet = get_wallet_testenv(&env, &client, None);
+        distribute_token(&sender_wallet, &env, &color_id, &client);
+
+        let receiver_wallet_config = get_wallet_config_testenv(&env, Some("tprv8ZgxMBicQKsPfKH3fHRJGBs9Vt2hMHfroZuZ5yYLYZgwvC3Hc8Wksn1HDinon77ZvDNEo25BEefQ6Ldgi4Nw29o1gP7pY8QzAyn1WQimrdc".to_string()));
+        let receiver_wallet = get_wallet_by_config(receiver_wallet_config, &env, &client);
+        let GetNewAddressResult {
+            public_key: receiver_public_key,
+            ..
+        } = receiver_wallet.get_new_address(None).unwrap();
+        let contract = |id: &str, content: &str| Contract {
+            contract_id: id.to_string(),
+            contract: content.to_string(),
+            payment_base: receiver_public_key.clone(),
+            payable: true,
+            encoding: None,
+        };
+
+        let p2c_address = sender_wallet
+            .calc_p2c_address(
+                receiver_public_key.clone(),
+                "paid contract".to_string(),
+                Some(color_id.to_string()),
+                None,
+            )
+            .unwrap();
+        sender_wallet
+            .transfer(
+                vec![TransferParams {
+                    amount: 10,
+                    to_address: p2c_address,
+                }],
+                vec![],
+            )
+            .expect("Failed to transfer");
+        wait_for_confirmation(&env, &client, 1);
+
+        receiver_wallet.set_contract_registry(Some(Box::new(StaticContractRegistry {
+            contracts: vec![
+                contract("paid", "paid contract"),
+                contract("unpaid", "unpaid contract"),
+            ],
+            requested: Arc::new(Mutex::new(Vec::new())),
+        })));
+        let result = receiver_wallet.sync().expect("Failed to sync");
+        assert_eq!(result.discovered_contracts, vec!["paid".to_string()]);
+        assert_eq!(
+            receiver_wallet
+                .contract_balance("paid".to_string())
                 .unwrap(),
-            10
+            vec![ColoredAmount {
+                color_id: Some(color_id.to_string()),
+                amount: 10,
+            }]
+        );
+        assert_eq!(
+            receiver_wallet
+                .get_contract("unpaid".to_string())
+                .unwrap_err(),
+            GetContractError::UnknownContract
         );
+
+        // The stored contracts are not discovered again
+        let result = receiver_wallet.sync().expect("Failed to sync");
+        assert!(result.discovered_contracts.is_empty());
     }
 
     #[test]
-    fn test_refund() {
+    fn test_refund_trust_layer_transfer() {
         let (env, color_id, client) = prepare_token();
         let sender_wallet = get_wallet_testenv(&env, &client, None);
         distribute_token(&sender_wallet, &env, &color_id, &client);
 
         let receiver_wallet_config = get_wallet_config_testenv(&env, Some("tprv8ZgxMBicQKsPfKH3fHRJGBs9Vt2hMHfroZuZ5yYLYZgwvC3Hc8Wksn1HDinon77ZvDNEo25BEefQ6Ldgi4Nw29o1gP7pY8QzAyn1WQimrdc".to_string()));
-        let receiver_wallet = get_wallet_by_config(receiver_wallet_config.clone(), &env, &client);
-
-        distribute_token(&receiver_wallet, &env, &color_id, &client);
-
-        // Receiver generate new public key and notify to the sender
+        let receiver_wallet = get_wallet_by_config(receiver_wallet_config, &env, &client);
         let GetNewAddressResult {
             public_key: receiver_public_key,
             ..
@@ -1986,27 +6955,106 @@ mod test {
             .get_new_address(Some(color_id.to_string()))
             .unwrap();
 
-        transfer_and_refund(
-            &sender_wallet,
-            &receiver_wallet,
-            &receiver_public_key,
-            &env,
-            &color_id,
-            &client,
-            "contract_id",
-            "contract",
+        let p2c_address = sender_wallet
+            .calc_p2c_address(
+                receiver_public_key.clone(),
+                "refund contract".to_string(),
+                Some(color_id.to_string()),
+                None,
+            )
+            .unwrap();
+        let transfer_txid = sender_wallet
+            .transfer(
+                vec![TransferParams {
+                    amount: 10,
+                    to_address: p2c_address.clone(),
+                }],
+                vec![],
+            )
+            .expect("Failed to transfer");
+        wait_for_confirmation(&env, &client, 1);
+
+        receiver_wallet
+            .store_contract(Contract {
+                contract_id: "refund_contract_id".to_string(),
+                contract: "refund contract".to_string(),
+                payment_base: receiver_public_key,
+                payable: false,
+                encoding: None,
+            })
+            .unwrap();
+        receiver_wallet.sync().expect("Failed to sync");
+
+        // The refund is sent to the uncolored address and colored by the refunded token.
+        let GetNewAddressResult {
+            address: refund_address,
+            ..
+        } = sender_wallet.get_new_address(None).unwrap();
+        assert_eq!(
+            receiver_wallet.refund_trust_layer_transfer(
+                transfer_txid.clone(),
+                refund_address.clone(),
+                None,
+                Some(p2c_address.clone()),
+            ),
+            Err(RefundTrustLayerTransferError::ContractNotPayable)
+        );
+        receiver_wallet
+            .update_contract("refund_contract_id".to_string(), true)
+            .unwrap();
+        // The refund address colored with the other color is rejected
+        let other_colored = add_color_to_address(
+            refund_address.clone(),
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            receiver_wallet.refund_trust_layer_transfer(
+                transfer_txid.clone(),
+                other_colored.clone(),
+                Some("refund_contract_id".to_string()),
+                None,
+            ),
+            Err(RefundTrustLayerTransferError::InvalidRefundAddress {
+                address: other_colored
+            })
         );
+        let refund_txid = receiver_wallet
+            .refund_trust_layer_transfer(
+                transfer_txid.clone(),
+                refund_address.clone(),
+                None,
+                Some(p2c_address),
+            )
+            .expect("Failed to refund");
 
-        // Refund multiple times from one wallet.
-        transfer_and_refund(
-            &sender_wallet,
-            &receiver_wallet,
-            &receiver_public_key,
-            &env,
-            &color_id,
-            &client,
-            "contract_id2",
-            "contract",
+        wait_for_confirmation(&env, &client, 1);
+        sender_wallet.sync().expect("Failed to sync");
+        receiver_wallet.sync().expect("Failed to sync");
+        assert_eq!(
+            sender_wallet.balance(Some(color_id.to_string())).unwrap(),
+            100
+        );
+        assert!(receiver_wallet
+            .contract_balance("refund_contract_id".to_string())
+            .unwrap()
+            .is_empty());
+
+        let report = sender_wallet
+            .check_trust_layer_refund_report(transfer_txid.clone(), color_id.to_string())
+            .unwrap();
+        assert_eq!(report.total_refund, 10);
+        assert_eq!(report.outputs[0].refund_txid, Some(refund_txid));
+
+        // The output is already refunded.
+        assert_eq!(
+            receiver_wallet.refund_trust_layer_transfer(
+                transfer_txid,
+                refund_address,
+                Some("refund_contract_id".to_string()),
+                None,
+            ),
+            Err(RefundTrustLayerTransferError::NoRefundableOutput)
         );
     }
 
@@ -2041,6 +7089,80 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_sign_message_compact() {
+        let wallet = get_wallet();
+        let message = "message".to_string();
+        let GetNewAddressResult {
+            address,
+            public_key,
+        } = wallet.get_new_address(None).unwrap();
+        let sig = wallet
+            .sign_message_compact(public_key.clone(), message.clone())
+            .unwrap();
+        assert_eq!(sig.len(), 88);
+
+        assert!(wallet
+            .verify_message(address.clone(), message.clone(), sig.clone())
+            .unwrap());
+        // The colored address of the same key is also verified
+        let colored_address = add_color_to_address(
+            address.clone(),
+            "c3ec2fd806701a3f55808cbec3922c38dafaa3070c48c803e9043ee3642c660b46".to_string(),
+        )
+        .unwrap();
+        assert!(wallet
+            .verify_message(colored_address, message.clone(), sig.clone())
+            .unwrap());
+        assert!(!wallet
+            .verify_message(address.clone(), "another message".to_string(), sig.clone())
+            .unwrap());
+        let GetNewAddressResult {
+            address: another_address,
+            ..
+        } = wallet.get_new_address(None).unwrap();
+        assert!(!wallet
+            .verify_message(another_address, message.clone(), sig.clone())
+            .unwrap());
+
+        assert_eq!(
+            wallet.sign_message_compact(
+                "039be0d2b0c3b6f7fad77f142257aee12b2a34047aa3191edc0424cd15e0fa15da".to_string(),
+                message.clone()
+            ),
+            Err(SignMessageError::PublicKeyNotFoundInWallet)
+        );
+        assert_eq!(
+            wallet.verify_message(address.clone(), message.clone(), "invalid".to_string()),
+            Err(VerifyMessageError::FailedToParseSignature)
+        );
+        assert_eq!(
+            wallet.verify_message(
+                "mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string(),
+                message,
+                sig
+            ),
+            Err(VerifyMessageError::WrongNetworkAddress {
+                address: "mfWxJ45yp2SFn7UciZyNpvDKrzbhyfKrY8".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_message_signed_by_other_implementation() {
+        let wallet = get_wallet();
+        // Signed with the compressed key of the private key
+        // 1e99423a4ed27608a15a2616a2b0e9e52ced330ac530edcc32c8ffc6a526aedd
+        let address = "1J7mdg5rbQyUHENYdx39WVWK7fsLpEoXZy".to_string();
+        let sig = "H9WV7Gdw6YeLbuOAZl5/Z4XzLPai8rMTQ1BMTp6WYi/wUJ1dmNGNr4w3fyalyuMb/u44Iz3HFQt+h68/bEhsJ5o=".to_string();
+        assert!(wallet
+            .verify_message(address.clone(), "Hello, Tapyrus!".to_string(), sig.clone())
+            .unwrap());
+        assert!(!wallet
+            .verify_message(address, "Hello, Bitcoin!".to_string(), sig)
+            .unwrap());
+    }
+
     #[test]
     fn test_verify_sign_error() {
         let wallet = get_wallet();
@@ -2055,6 +7177,86 @@ mod test {
         );
     }
 
+    struct ChannelListener(Mutex<mpsc::Sender<String>>);
+
+    impl NotificationListener for ChannelListener {
+        fn on_new_block(&self, _height: u32, _block_hash: String) {}
+
+        fn on_new_transaction(&self, txid: String) {
+            self.0.lock().unwrap().send(txid).unwrap();
+        }
+
+        fn on_transaction_confirmed(&self, _txid: String, _height: u32) {}
+    }
+
+    #[test]
+    fn test_start_notification_listener_unsupported_backend() {
+        let wallet = Arc::new(get_wallet());
+        let (sender, _receiver) = mpsc::channel();
+        assert_eq!(
+            Err(StartNotificationListenerError::UnsupportedBackend),
+            wallet.start_notification_listener(Box::new(ChannelListener(Mutex::new(sender))))
+        );
+    }
+
+    struct ChannelSyncListener(Mutex<mpsc::Sender<Result<SyncResult, String>>>);
+
+    impl SyncListener for ChannelSyncListener {
+        fn on_sync_completed(&self, result: SyncResult) {
+            self.0.lock().unwrap().send(Ok(result)).unwrap();
+        }
+
+        fn on_sync_failed(&self, cause_description: String) {
+            self.0.lock().unwrap().send(Err(cause_description)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_background_sync() {
+        // The esplora is not running, so the sync fails.
+        let wallet = Arc::new(get_wallet());
+        let (sender, receiver) = mpsc::channel();
+        assert_eq!(
+            Err(StartBackgroundSyncError::InvalidInterval),
+            wallet.clone().start_background_sync(
+                0,
+                0,
+                Box::new(ChannelSyncListener(Mutex::new(sender.clone())))
+            )
+        );
+
+        wallet
+            .clone()
+            .start_background_sync(1, 2, Box::new(ChannelSyncListener(Mutex::new(sender))))
+            .expect("Failed to start background sync");
+        assert_eq!(
+            Err(StartBackgroundSyncError::AlreadyStarted),
+            wallet.clone().start_background_sync(
+                1,
+                2,
+                Box::new(ChannelSyncListener(Mutex::new(mpsc::channel().0)))
+            )
+        );
+        let result = receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Sync result is not received");
+        assert!(result.is_err());
+
+        // The wallet can be used while the background sync is running
+        assert_eq!(wallet.balance(None).unwrap(), 0);
+
+        wallet.stop_background_sync();
+        wallet
+            .clone()
+            .start_background_sync(
+                1,
+                2,
+                Box::new(ChannelSyncListener(Mutex::new(mpsc::channel().0))),
+            )
+            .expect("Failed to restart background sync");
+        wallet.stop_background_sync();
+    }
+
     // Electrum backend tests
 
     fn get_wallet_by_config_electrum(
@@ -2147,6 +7349,7 @@ mod test {
                 public_key.clone(),
                 "content".to_string(),
                 Some(color_id.clone().to_string()),
+                None,
             )
             .unwrap();
 
@@ -2165,6 +7368,7 @@ mod test {
             contract: "content".to_string(),
             payment_base: public_key,
             payable: false,
+            encoding: None,
         };
 
         wallet
@@ -2205,4 +7409,179 @@ mod test {
             100
         );
     }
+
+    #[test]
+    fn test_notification_listener_electrum() {
+        let (env, _color_id, client) = prepare_token();
+        let wallet = Arc::new(get_wallet_testenv_electrum(&env, &client, None));
+
+        let (sender, receiver) = mpsc::channel();
+        wallet
+            .clone()
+            .start_notification_listener(Box::new(ChannelListener(Mutex::new(sender))))
+            .expect("Failed to start notification listener");
+        assert_eq!(
+            Err(StartNotificationListenerError::AlreadyStarted),
+            wallet
+                .clone()
+                .start_notification_listener(Box::new(ChannelListener(Mutex::new(
+                    mpsc::channel().0
+                ))))
+        );
+
+        let GetNewAddressResult { address, .. } = wallet.get_new_address(None).unwrap();
+        let address = Address::from_str(&address).unwrap().assume_checked();
+        env.tapyrusd
+            .client
+            .send_to_address(
+                &address,
+                Amount::from_tap(1000),
+                None,
+                None,
+                None,
+                None,
+                Some(1),
+                None,
+            )
+            .unwrap();
+
+        let txid = receiver
+            .recv_timeout(Duration::from_secs(30))
+            .expect("Notification is not received");
+        wallet.sync().expect("Failed to sync");
+        assert!(wallet
+            .get_wallet()
+            .transactions()
+            .any(|tx| tx.tx_node.txid.to_string() == txid));
+
+        wallet.stop_notification_listener();
+    }
+
+    #[test]
+    fn test_sync_addresses() {
+        let (env, _color_id, client) = prepare_token();
+        let wallet = get_wallet_testenv(&env, &client, None);
+
+        let GetNewAddressResult { address, .. } = wallet.get_new_address(None).unwrap();
+        let checked_address = Address::from_str(&address).unwrap().assume_checked();
+        env.tapyrusd
+            .client
+            .send_to_address(
+                &checked_address,
+                Amount::from_tap(1000),
+                None,
+                None,
+                None,
+                None,
+                Some(1),
+                None,
+            )
+            .unwrap();
+        wait_for_confirmation(&env, &client, 1);
+
+        let result = wallet
+            .sync_addresses(vec![address])
+            .expect("Failed to sync addresses");
+        assert_eq!(result.new_transactions, 1);
+        assert_eq!(wallet.balance(None).unwrap(), 21000);
+    }
+
+    #[test]
+    fn test_transfer_with_options() {
+        let (env, _color_id, client) = prepare_token();
+        let wallet = get_wallet_testenv(&env, &client, None);
+
+        let GetNewAddressResult { address, .. } = wallet.get_new_address(None).unwrap();
+        let address = Address::from_str(&address).unwrap().assume_checked();
+        env.tapyrusd
+            .client
+            .send_to_address(
+                &address,
+                Amount::from_tap(20000),
+                None,
+                None,
+                None,
+                None,
+                Some(1),
+                None,
+            )
+            .unwrap();
+        wait_for_confirmation(&env, &client, 1);
+        wallet.sync().expect("Failed to sync");
+
+        let to_address: String = env.tapyrusd.client.call("getnewaddress", &[]).unwrap();
+
+        // Exact spend is not possible because of the fee
+        let options = TransferOptions {
+            allow_change: false,
+            ..Default::default()
+        };
+        assert!(matches!(
+            wallet.transfer_with_options(
+                vec![TransferParams {
+                    amount: 1000,
+                    to_address: to_address.clone(),
+                }],
+                vec![],
+                options,
+            ),
+            Err(TransferError::ChangeNotAllowed)
+        ));
+
+        // Send the change to the external address
+        let GetNewAddressResult {
+            address: change_address,
+            ..
+        } = wallet.get_new_address(None).unwrap();
+        let options = TransferOptions {
+            change_address: Some(change_address.clone()),
+            ..Default::default()
+        };
+        wallet
+            .transfer_with_options(
+                vec![TransferParams {
+                    amount: 1000,
+                    to_address,
+                }],
+                vec![],
+                options,
+            )
+            .expect("Failed to transfer");
+
+        wait_for_confirmation(&env, &client, 1);
+        wallet.sync().expect("Failed to sync");
+        let change = wallet
+            .list_addresses(Keychain::External)
+            .into_iter()
+            .find(|info| info.address == change_address)
+            .unwrap();
+        assert!(change.used);
+        assert!(wallet.list_addresses(Keychain::Internal).is_empty());
+
+        // The recipient which is the change address of the wallet is not the change output
+        let recipient = wallet.get_change_address(None).unwrap();
+        let options = TransferOptions {
+            change_address: Some(change_address.clone()),
+            ..Default::default()
+        };
+        wallet
+            .transfer_with_options(
+                vec![TransferParams {
+                    amount: 1000,
+                    to_address: recipient.address.clone(),
+                }],
+                vec![],
+                options,
+            )
+            .expect("Failed to transfer");
+
+        wait_for_confirmation(&env, &client, 1);
+        wallet.sync().expect("Failed to sync");
+        let recipient = wallet
+            .list_addresses(Keychain::Internal)
+            .into_iter()
+            .find(|info| info.address == recipient.address)
+            .unwrap();
+        assert!(recipient.used);
+    }
 }